@@ -0,0 +1,82 @@
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ROOT_CHAR: u8 = b'\\';
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ONES_OP: u8 = 0xff;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+
+/// SLP_TYPa and SLP_TYPb values that put the system into a sleep state.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Reads a constant integer, only the low byte of wider ones is kept.
+fn read_integer(aml: &[u8], at: &mut usize) -> Option<u8> {
+    let op = *aml.get(*at)?;
+    *at += 1;
+    let (value, len) = match op {
+        ZERO_OP => (0, 0),
+        ONE_OP => (1, 0),
+        ONES_OP => (0xff, 0),
+        BYTE_PREFIX => (*aml.get(*at)?, 1),
+        WORD_PREFIX => (*aml.get(*at)?, 2),
+        DWORD_PREFIX => (*aml.get(*at)?, 4),
+        _ => return None,
+    };
+    *at += len;
+    Some(value)
+}
+
+fn parse_s5_package(aml: &[u8], mut at: usize) -> Option<SleepType> {
+    if *aml.get(at)? != PACKAGE_OP {
+        return None;
+    }
+    at += 1;
+    // PkgLength, the top two bits of the lead byte count the bytes following
+    let extra = (*aml.get(at)? >> 6) as usize;
+    at += 1 + extra;
+    // NumElements
+    at += 1;
+    let a = read_integer(aml, &mut at)?;
+    let b = read_integer(aml, &mut at)?;
+    Some(SleepType { a, b })
+}
+
+/// Finds the `\_S5` object in a DSDT body without running an AML
+/// interpreter. Firmware declares it as a plain package of constants, which
+/// is enough to power off.
+pub fn find_s5(aml: &[u8]) -> Option<SleepType> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, name)| *name == b"_S5_")
+        .filter(|&(at, _)| {
+            (at >= 1 && aml[at - 1] == NAME_OP)
+                || (at >= 2 && aml[at - 1] == ROOT_CHAR && aml[at - 2] == NAME_OP)
+        })
+        .find_map(|(at, _)| parse_s5_package(aml, at + 4))
+}
+
+#[test_case]
+fn s5_package_is_found() {
+    // Name (_S5, Package (0x04) { 0x05, Zero, Zero, Zero }) after some noise
+    let aml = [
+        0x10, 0x5f, 0x53, 0x35, 0x5f, 0x00, NAME_OP, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x0a,
+        0x04, BYTE_PREFIX, 0x05, ZERO_OP, ZERO_OP, ZERO_OP,
+    ];
+    assert_eq!(find_s5(&aml), Some(SleepType { a: 5, b: 0 }));
+
+    let rooted = [
+        NAME_OP, ROOT_CHAR, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x06, 0x02, ONE_OP, BYTE_PREFIX,
+        0x07,
+    ];
+    assert_eq!(find_s5(&rooted), Some(SleepType { a: 1, b: 7 }));
+
+    // a method body referring to _S5 is not its definition
+    assert_eq!(find_s5(&[0x70, b'_', b'S', b'5', b'_', 0x60]), None);
+    assert_eq!(find_s5(&[NAME_OP, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x04]), None);
+}
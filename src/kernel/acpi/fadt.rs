@@ -0,0 +1,174 @@
+use super::{expect, read_u16, read_u32, read_u64, AcpiError};
+use x86_64::PhysAddr;
+
+// the ACPI 1.0 table ends after the flags
+const FADT_V1_SIZE: usize = 116;
+
+const DSDT: usize = 40;
+const SCI_INT: usize = 46;
+const SMI_CMD: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const PM1A_CNT_BLK: usize = 64;
+const PM1B_CNT_BLK: usize = 68;
+const PM_TMR_BLK: usize = 76;
+const CENTURY: usize = 108;
+const IAPC_BOOT_ARCH: usize = 109;
+const FLAGS: usize = 112;
+const RESET_REG: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+
+const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+const BOOT_ARCH_8042: u16 = 1 << 1;
+const BOOT_ARCH_NO_CMOS_RTC: u16 = 1 << 5;
+
+/// Which bus a `GenericAddress` lives on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RegisterSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// ACPI's way of pointing at a register.
+#[derive(Copy, Clone, Debug)]
+pub struct GenericAddress {
+    pub space: RegisterSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub(super) fn parse(bytes: &[u8]) -> GenericAddress {
+        GenericAddress {
+            space: match bytes[0] {
+                0 => RegisterSpace::SystemMemory,
+                1 => RegisterSpace::SystemIo,
+                2 => RegisterSpace::PciConfig,
+                other => RegisterSpace::Other(other),
+            },
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+}
+
+/// The ACPI power management timer, a 3.579545 MHz counter.
+#[derive(Copy, Clone, Debug)]
+pub struct PmTimer {
+    pub port: u16,
+    /// 32 bits wide rather than 24.
+    pub extended: bool,
+}
+
+/// The Fixed ACPI Description Table, the parts of it the kernel uses.
+#[derive(Copy, Clone, Debug)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    /// Port to write `acpi_enable` to for switching to ACPI mode, 0 if the
+    /// system is always in it.
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    pub pm1a_control: u16,
+    pub pm1b_control: Option<u16>,
+    pub pm_timer: Option<PmTimer>,
+    /// CMOS register holding the century, if there is one.
+    pub century_register: Option<u8>,
+    pub has_8042: bool,
+    pub has_cmos_rtc: bool,
+    /// Register and value that reset the system when written.
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    pub fn parse(table: &[u8]) -> Result<Fadt, AcpiError> {
+        expect(table, b"FACP", FADT_V1_SIZE)?;
+        let revision = table[8];
+        let flags = read_u32(table, FLAGS);
+
+        let x_dsdt = if table.len() >= X_DSDT + 8 {
+            read_u64(table, X_DSDT)
+        } else {
+            0
+        };
+        let dsdt = if x_dsdt != 0 {
+            x_dsdt
+        } else {
+            read_u32(table, DSDT) as u64
+        };
+        // the boot architecture flags only exist from revision 2 on, before
+        // that every PC had both
+        let boot_arch = if revision >= 2 {
+            read_u16(table, IAPC_BOOT_ARCH)
+        } else {
+            BOOT_ARCH_8042
+        };
+        let reset = if flags & FLAG_RESET_REG_SUP != 0 && table.len() > RESET_VALUE {
+            Some((GenericAddress::parse(&table[RESET_REG..]), table[RESET_VALUE]))
+        } else {
+            None
+        };
+
+        Ok(Fadt {
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: read_u16(table, SCI_INT),
+            smi_command: read_u32(table, SMI_CMD) as u16,
+            acpi_enable: table[ACPI_ENABLE],
+            pm1a_control: read_u32(table, PM1A_CNT_BLK) as u16,
+            pm1b_control: Some(read_u32(table, PM1B_CNT_BLK) as u16).filter(|&port| port != 0),
+            pm_timer: Some(read_u32(table, PM_TMR_BLK) as u16)
+                .filter(|&port| port != 0)
+                .map(|port| PmTimer {
+                    port,
+                    extended: flags & FLAG_TMR_VAL_EXT != 0,
+                }),
+            century_register: Some(table[CENTURY]).filter(|&reg| reg != 0),
+            has_8042: boot_arch & BOOT_ARCH_8042 != 0,
+            has_cmos_rtc: boot_arch & BOOT_ARCH_NO_CMOS_RTC == 0,
+            reset,
+        })
+    }
+}
+
+#[test_case]
+fn fadt_fields_are_parsed() {
+    let mut body = alloc::vec![0u8; 244 - super::SDT_HEADER_SIZE];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        let at = offset - super::SDT_HEADER_SIZE;
+        body[at..at + bytes.len()].copy_from_slice(bytes);
+    };
+    put(DSDT, &0x1234_0000u32.to_le_bytes());
+    put(PM1A_CNT_BLK, &0x604u32.to_le_bytes());
+    put(PM_TMR_BLK, &0x608u32.to_le_bytes());
+    put(CENTURY, &[0x32]);
+    put(IAPC_BOOT_ARCH, &BOOT_ARCH_8042.to_le_bytes());
+    put(FLAGS, &(FLAG_RESET_REG_SUP | FLAG_TMR_VAL_EXT).to_le_bytes());
+    put(RESET_REG, &[1, 8, 0, 1, 0xf9, 0x0c, 0, 0, 0, 0, 0, 0]);
+    put(RESET_VALUE, &[0x0f]);
+    let table = super::build_table(b"FACP", 3, &body);
+
+    let fadt = Fadt::parse(&table).unwrap();
+    assert_eq!(fadt.dsdt, PhysAddr::new(0x1234_0000));
+    assert_eq!(fadt.pm1a_control, 0x604);
+    assert_eq!(fadt.pm1b_control, None);
+    assert!(fadt.pm_timer.unwrap().extended);
+    assert_eq!(fadt.century_register, Some(0x32));
+    assert!(fadt.has_8042 && fadt.has_cmos_rtc);
+    let (reset, value) = fadt.reset.unwrap();
+    assert_eq!(reset.space, RegisterSpace::SystemIo);
+    assert_eq!((reset.address, value), (0xcf9, 0x0f));
+
+    let v1 = super::build_table(b"FACP", 1, &body[..FADT_V1_SIZE - super::SDT_HEADER_SIZE]);
+    assert!(Fadt::parse(&v1).unwrap().reset.is_none());
+    assert_eq!(
+        Fadt::parse(&v1[..FADT_V1_SIZE - 1]).err(),
+        Some(AcpiError::TooShort)
+    );
+}
@@ -0,0 +1,34 @@
+use super::{expect, read_u16, read_u32, AcpiError, GenericAddress, SDT_HEADER_SIZE};
+
+const HPET_TABLE_SIZE: usize = SDT_HEADER_SIZE + 20;
+
+/// The HPET Description Table for one timer block.
+#[derive(Copy, Clone, Debug)]
+pub struct Hpet {
+    /// Register block, always in system memory.
+    pub base: GenericAddress,
+    pub hpet_number: u8,
+    pub vendor_id: u16,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    /// Smallest period, in main counter ticks, periodic mode can be set to
+    /// without losing interrupts.
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub fn parse(table: &[u8]) -> Result<Hpet, AcpiError> {
+        expect(table, b"HPET", HPET_TABLE_SIZE)?;
+        let block_id = read_u32(table, SDT_HEADER_SIZE);
+        Ok(Hpet {
+            base: GenericAddress::parse(&table[SDT_HEADER_SIZE + 4..]),
+            hpet_number: table[SDT_HEADER_SIZE + 16],
+            vendor_id: (block_id >> 16) as u16,
+            comparators: ((block_id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            minimum_tick: read_u16(table, SDT_HEADER_SIZE + 17),
+        })
+    }
+}
@@ -0,0 +1,214 @@
+use super::{expect, read_u16, read_u32, read_u64, AcpiError, SDT_HEADER_SIZE};
+use crate::kernel::irqchip::{ApicConfig, IoApicInfo, IsaOverride};
+use heapless::consts::{U16, U64, U8};
+use heapless::Vec;
+use x86_64::PhysAddr;
+
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+const LOCAL_X2APIC_NMI: u8 = 10;
+
+const PCAT_COMPAT: u32 = 1;
+const PROCESSOR_ENABLED: u32 = 1;
+
+/// A cpu the firmware knows of.
+#[derive(Copy, Clone, Debug)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    /// Disabled cpus must not be started.
+    pub enabled: bool,
+}
+
+/// An APIC LINT pin wired to NMI.
+#[derive(Copy, Clone, Debug)]
+pub struct LocalApicNmi {
+    /// `None` for every cpu.
+    pub processor_id: Option<u32>,
+    pub lint: u8,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The Multiple APIC Description Table.
+#[derive(Clone, Debug)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    /// Whether the system also has the dual 8259 setup.
+    pub pcat_compat: bool,
+    pub processors: Vec<Processor, U64>,
+    pub io_apics: Vec<IoApicInfo, U8>,
+    pub overrides: Vec<IsaOverride, U16>,
+    pub nmis: Vec<LocalApicNmi, U16>,
+}
+
+/// Decodes MPS INTI flags. Conforming means the ISA default, active high and
+/// edge triggered.
+fn inti_flags(flags: u16) -> (bool, bool) {
+    let active_low = flags & 0b11 == 0b11;
+    let level_triggered = (flags >> 2) & 0b11 == 0b11;
+    (active_low, level_triggered)
+}
+
+impl Madt {
+    pub fn parse(table: &[u8]) -> Result<Madt, AcpiError> {
+        expect(table, b"APIC", SDT_HEADER_SIZE + 8)?;
+        let mut madt = Madt {
+            local_apic: PhysAddr::new(read_u32(table, SDT_HEADER_SIZE) as u64),
+            pcat_compat: read_u32(table, SDT_HEADER_SIZE + 4) & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut offset = SDT_HEADER_SIZE + 8;
+        while offset + 2 <= table.len() {
+            let kind = table[offset];
+            let len = table[offset + 1] as usize;
+            if len < 2 || offset + len > table.len() {
+                return Err(AcpiError::TooShort);
+            }
+            let entry = &table[offset..offset + len];
+            offset += len;
+            // entries are only ever extended, so longer ones are fine
+            let full = match kind {
+                LOCAL_APIC => entry.len() >= 8,
+                IO_APIC | LOCAL_APIC_ADDRESS | LOCAL_X2APIC_NMI => entry.len() >= 12,
+                SOURCE_OVERRIDE => entry.len() >= 10,
+                LOCAL_APIC_NMI => entry.len() >= 6,
+                LOCAL_X2APIC => entry.len() >= 16,
+                _ => true,
+            };
+            if !full {
+                return Err(AcpiError::TooShort);
+            }
+
+            let pushed = match kind {
+                LOCAL_APIC => madt
+                    .processors
+                    .push(Processor {
+                        processor_id: entry[2] as u32,
+                        apic_id: entry[3] as u32,
+                        enabled: read_u32(entry, 4) & PROCESSOR_ENABLED != 0,
+                    })
+                    .is_ok(),
+                LOCAL_X2APIC => madt
+                    .processors
+                    .push(Processor {
+                        processor_id: read_u32(entry, 12),
+                        apic_id: read_u32(entry, 4),
+                        enabled: read_u32(entry, 8) & PROCESSOR_ENABLED != 0,
+                    })
+                    .is_ok(),
+                IO_APIC => madt
+                    .io_apics
+                    .push(IoApicInfo {
+                        id: entry[2],
+                        address: PhysAddr::new(read_u32(entry, 4) as u64),
+                        gsi_base: read_u32(entry, 8),
+                    })
+                    .is_ok(),
+                // only bus 0, ISA, is defined
+                SOURCE_OVERRIDE if entry[2] == 0 => {
+                    let (active_low, level_triggered) = inti_flags(read_u16(entry, 8));
+                    madt.overrides
+                        .push(IsaOverride {
+                            irq: entry[3],
+                            gsi: read_u32(entry, 4),
+                            active_low,
+                            level_triggered,
+                        })
+                        .is_ok()
+                }
+                LOCAL_APIC_NMI => {
+                    let (active_low, level_triggered) = inti_flags(read_u16(entry, 3));
+                    madt.nmis
+                        .push(LocalApicNmi {
+                            processor_id: Some(entry[2] as u32).filter(|&id| id != 0xff),
+                            lint: entry[5],
+                            active_low,
+                            level_triggered,
+                        })
+                        .is_ok()
+                }
+                LOCAL_X2APIC_NMI => {
+                    let (active_low, level_triggered) = inti_flags(read_u16(entry, 2));
+                    madt.nmis
+                        .push(LocalApicNmi {
+                            processor_id: Some(read_u32(entry, 4)).filter(|&id| id != !0),
+                            lint: entry[8],
+                            active_low,
+                            level_triggered,
+                        })
+                        .is_ok()
+                }
+                LOCAL_APIC_ADDRESS => {
+                    madt.local_apic = PhysAddr::new(read_u64(entry, 4));
+                    true
+                }
+                _ => true,
+            };
+            if !pushed {
+                log::warn!("too many madt entries of type {}, ignoring", kind);
+            }
+        }
+        Ok(madt)
+    }
+
+    /// The APIC setup the table describes, `None` if it has no I/O APIC to
+    /// route legacy IRQs through.
+    pub fn apic_config(&self) -> Option<ApicConfig> {
+        if self.io_apics.is_empty() {
+            return None;
+        }
+        Some(ApicConfig {
+            local_apic: self.local_apic,
+            io_apics: self.io_apics.clone(),
+            overrides: self.overrides.clone(),
+        })
+    }
+}
+
+#[test_case]
+fn madt_entries_are_parsed() {
+    let mut body = alloc::vec::Vec::new();
+    body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    body.extend_from_slice(&PCAT_COMPAT.to_le_bytes());
+    // two cpus, the second one disabled
+    body.extend_from_slice(&[LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+    body.extend_from_slice(&[LOCAL_APIC, 8, 1, 1, 0, 0, 0, 0]);
+    body.extend_from_slice(&[IO_APIC, 12, 2, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0]);
+    // irq 0 to gsi 2, irq 9 to gsi 9 level triggered and active low
+    body.extend_from_slice(&[SOURCE_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+    body.extend_from_slice(&[SOURCE_OVERRIDE, 10, 0, 9, 9, 0, 0, 0, 0b1111, 0]);
+    body.extend_from_slice(&[LOCAL_APIC_NMI, 6, 0xff, 0, 0, 1]);
+    // an entry type from the future is skipped
+    body.extend_from_slice(&[0x7f, 4, 0, 0]);
+    let table = super::build_table(b"APIC", 4, &body);
+
+    let madt = Madt::parse(&table).unwrap();
+    assert_eq!(madt.local_apic, PhysAddr::new(0xfee0_0000));
+    assert!(madt.pcat_compat);
+    assert_eq!(madt.processors.len(), 2);
+    assert!(madt.processors[0].enabled && !madt.processors[1].enabled);
+    assert_eq!(madt.processors[1].apic_id, 1);
+    assert_eq!(madt.io_apics[0].address, PhysAddr::new(0xfec0_0000));
+    assert_eq!(madt.nmis[0].processor_id, None);
+    assert_eq!(madt.nmis[0].lint, 1);
+
+    let config = madt.apic_config().unwrap();
+    assert_eq!(config.resolve(0).unwrap().gsi, 2);
+    let sci = config.resolve(9).unwrap();
+    assert!(sci.level_triggered && sci.active_low);
+    assert_eq!(config.resolve(4).unwrap().gsi, 4);
+
+    let mut truncated = body.clone();
+    truncated.extend_from_slice(&[IO_APIC, 12, 0, 0]);
+    let table = super::build_table(b"APIC", 4, &truncated);
+    assert_eq!(Madt::parse(&table).err(), Some(AcpiError::TooShort));
+}
@@ -0,0 +1,304 @@
+mod dsdt;
+mod fadt;
+mod hpet;
+mod madt;
+
+use super::memory::phys_to_virt;
+use crate::util::init_cell::InitCell;
+use core::convert::TryInto;
+use heapless::{consts::U64, Vec};
+use x86_64::PhysAddr;
+
+pub use dsdt::SleepType;
+pub use fadt::{Fadt, GenericAddress, PmTimer, RegisterSpace};
+pub use hpet::Hpet;
+pub use madt::{LocalApicNmi, Madt, Processor};
+
+const SDT_HEADER_SIZE: usize = 36;
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AcpiError {
+    BadChecksum,
+    TooShort,
+    BadSignature,
+}
+
+/// The header every system description table starts with.
+#[derive(Copy, Clone, Debug)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+}
+
+impl SdtHeader {
+    fn parse(table: &[u8]) -> SdtHeader {
+        SdtHeader {
+            signature: table[0..4].try_into().unwrap(),
+            length: read_u32(table, 4),
+            revision: table[8],
+            oem_id: table[10..16].try_into().unwrap(),
+        }
+    }
+
+    pub fn signature(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+}
+
+/// The tables the kernel understands. Any of them may be missing.
+pub struct Tables {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    /// How to enter S5, soft off.
+    pub s5: Option<SleepType>,
+}
+
+static TABLES: InitCell<Tables> = InitCell::new();
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn phys_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(addr).as_ptr(), len) }
+}
+
+/// Checks that `table` holds a whole table with a valid checksum, and trims
+/// it to the length the header gives.
+fn validate(table: &[u8]) -> Result<&[u8], AcpiError> {
+    if table.len() < SDT_HEADER_SIZE {
+        return Err(AcpiError::TooShort);
+    }
+    let length = read_u32(table, 4) as usize;
+    if length < SDT_HEADER_SIZE || length > table.len() {
+        return Err(AcpiError::TooShort);
+    }
+    let table = &table[..length];
+    if !checksum_ok(table) {
+        return Err(AcpiError::BadChecksum);
+    }
+    Ok(table)
+}
+
+/// Checks a validated table is a `signature` table of at least `min_len`
+/// bytes.
+fn expect(table: &[u8], signature: &[u8; 4], min_len: usize) -> Result<(), AcpiError> {
+    if &table[0..4] != signature {
+        Err(AcpiError::BadSignature)
+    } else if table.len() < min_len {
+        Err(AcpiError::TooShort)
+    } else {
+        Ok(())
+    }
+}
+
+fn read_table(addr: PhysAddr) -> Result<&'static [u8], AcpiError> {
+    let header = phys_bytes(addr, SDT_HEADER_SIZE);
+    let length = read_u32(header, 4) as usize;
+    validate(phys_bytes(addr, length.max(SDT_HEADER_SIZE)))
+}
+
+struct Rsdp {
+    revision: u8,
+    oem_id: [u8; 6],
+    rsdt: u32,
+    xsdt: Option<u64>,
+}
+
+impl Rsdp {
+    fn parse(bytes: &[u8]) -> Option<Rsdp> {
+        if &bytes[0..8] != b"RSD PTR " || !checksum_ok(&bytes[..RSDP_V1_SIZE]) {
+            return None;
+        }
+        let revision = bytes[15];
+        let xsdt = if revision >= 2 {
+            let length = read_u32(bytes, 20) as usize;
+            if length < RSDP_V2_SIZE || length > bytes.len() || !checksum_ok(&bytes[..length]) {
+                return None;
+            }
+            Some(read_u64(bytes, 24)).filter(|&xsdt| xsdt != 0)
+        } else {
+            None
+        };
+        Some(Rsdp {
+            revision,
+            oem_id: bytes[9..15].try_into().unwrap(),
+            rsdt: read_u32(bytes, 16),
+            xsdt,
+        })
+    }
+
+    /// Looks for the RSDP where the BIOS leaves it: the first KiB of the
+    /// EBDA, then the read-only area below 1M. It sits on a 16 byte boundary.
+    fn find() -> Option<Rsdp> {
+        let ebda = (read_u16(phys_bytes(PhysAddr::new(0x40e), 2), 0) as u64) << 4;
+        let areas = [(ebda, ebda + 0x400), (0xe_0000, 0x10_0000)];
+        for &(start, end) in areas.iter().filter(|&&(start, _)| start != 0) {
+            for addr in (start..end).step_by(16) {
+                if let Some(rsdp) = Rsdp::parse(phys_bytes(PhysAddr::new(addr), RSDP_V2_SIZE)) {
+                    return Some(rsdp);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Addresses of the tables the root table points to.
+fn table_addrs(rsdp: &Rsdp) -> Result<Vec<PhysAddr, U64>, AcpiError> {
+    let (root, entry_size) = match rsdp.xsdt {
+        Some(xsdt) => (read_table(PhysAddr::new(xsdt))?, 8),
+        None => (read_table(PhysAddr::new(rsdp.rsdt as u64))?, 4),
+    };
+    let mut addrs = Vec::new();
+    for offset in (SDT_HEADER_SIZE..root.len() - root.len() % entry_size).step_by(entry_size) {
+        let addr = if entry_size == 8 {
+            read_u64(root, offset)
+        } else {
+            read_u32(root, offset) as u64
+        };
+        if addrs.push(PhysAddr::new(addr)).is_err() {
+            log::warn!("too many acpi tables, ignoring the rest");
+            break;
+        }
+    }
+    Ok(addrs)
+}
+
+pub fn init() {
+    crate::call_stack!();
+    let mut tables = Tables {
+        revision: 0,
+        oem_id: [0; 6],
+        madt: None,
+        fadt: None,
+        hpet: None,
+        s5: None,
+    };
+    let rsdp = match Rsdp::find() {
+        Some(rsdp) => rsdp,
+        None => {
+            log::warn!("no acpi rsdp found");
+            TABLES.init(tables);
+            return;
+        }
+    };
+    tables.revision = rsdp.revision;
+    tables.oem_id = rsdp.oem_id;
+
+    let addrs = table_addrs(&rsdp).unwrap_or_else(|err| {
+        log::warn!("bad acpi root table: {:?}", err);
+        Vec::new()
+    });
+    for &addr in addrs.iter() {
+        let table = match read_table(addr) {
+            Ok(table) => table,
+            Err(err) => {
+                log::warn!("skipping acpi table at {:?}: {:?}", addr, err);
+                continue;
+            }
+        };
+        let header = SdtHeader::parse(table);
+        log::info!(
+            "acpi table {} at {:?}, revision {}",
+            header.signature(),
+            addr,
+            header.revision
+        );
+        let parsed = match &header.signature {
+            b"APIC" => Madt::parse(table).map(|madt| tables.madt = Some(madt)),
+            b"FACP" => Fadt::parse(table).map(|fadt| tables.fadt = Some(fadt)),
+            b"HPET" => Hpet::parse(table).map(|hpet| tables.hpet = Some(hpet)),
+            _ => Ok(()),
+        };
+        if let Err(err) = parsed {
+            log::warn!("bad acpi table {}: {:?}", header.signature(), err);
+        }
+    }
+    if let Some(fadt) = &tables.fadt {
+        match read_table(fadt.dsdt).and_then(|dsdt| expect(dsdt, b"DSDT", 0).map(|_| dsdt)) {
+            Ok(dsdt) => tables.s5 = dsdt::find_s5(&dsdt[SDT_HEADER_SIZE..]),
+            Err(err) => log::warn!("bad dsdt: {:?}", err),
+        }
+    }
+    TABLES.init(tables);
+}
+
+pub fn tables() -> &'static Tables {
+    TABLES.get()
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    TABLES.get().madt.as_ref()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    TABLES.get().fadt.as_ref()
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    TABLES.get().hpet.as_ref()
+}
+
+pub fn s5() -> Option<SleepType> {
+    TABLES.get().s5
+}
+
+/// A table with a valid header and checksum around `body`.
+#[cfg(test)]
+fn build_table(signature: &[u8; 4], revision: u8, body: &[u8]) -> alloc::vec::Vec<u8> {
+    let mut table = alloc::vec![0u8; SDT_HEADER_SIZE];
+    table[0..4].copy_from_slice(signature);
+    table[8] = revision;
+    table[10..16].copy_from_slice(b"NGOS  ");
+    table.extend_from_slice(body);
+    let length = table.len() as u32;
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    let sum = table.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    table[9] = 0u8.wrapping_sub(sum);
+    table
+}
+
+#[test_case]
+fn tables_are_validated() {
+    let table = build_table(b"TEST", 1, &[1, 2, 3]);
+    assert_eq!(validate(&table).map(|t| t.len()), Ok(SDT_HEADER_SIZE + 3));
+    assert_eq!(validate(&table[..SDT_HEADER_SIZE]), Err(AcpiError::TooShort));
+
+    let mut corrupt = table.clone();
+    corrupt[SDT_HEADER_SIZE] ^= 0x80;
+    assert_eq!(validate(&corrupt), Err(AcpiError::BadChecksum));
+
+    // trailing bytes past the header's length are not part of the table
+    let mut padded = table;
+    padded.push(0xff);
+    assert_eq!(validate(&padded).map(|t| t.len()), Ok(SDT_HEADER_SIZE + 3));
+}
+
+#[test_case]
+fn firmware_tables_are_found() {
+    let madt = madt().expect("no madt");
+    assert!(madt.processors.iter().any(|cpu| cpu.enabled));
+    assert!(!madt.io_apics.is_empty());
+    assert!(fadt().is_some());
+    assert!(s5().is_some());
+}
@@ -0,0 +1,325 @@
+use super::int::{ContextKind, InterruptContextHandle};
+use super::{gdt, memory, sched};
+use core::fmt;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode};
+
+const DIVIDE_ERROR: u8 = 0;
+const DEBUG: u8 = 1;
+const BREAKPOINT: u8 = 3;
+const OVERFLOW: u8 = 4;
+const BOUND_RANGE_EXCEEDED: u8 = 5;
+const INVALID_OPCODE: u8 = 6;
+const DEVICE_NOT_AVAILABLE: u8 = 7;
+const DOUBLE_FAULT: u8 = 8;
+const SEGMENT_NOT_PRESENT: u8 = 11;
+const STACK_SEGMENT_FAULT: u8 = 12;
+const GENERAL_PROTECTION_FAULT: u8 = 13;
+const PAGE_FAULT: u8 = 14;
+const X87_FLOATING_POINT: u8 = 16;
+const ALIGNMENT_CHECK: u8 = 17;
+const SIMD_FLOATING_POINT: u8 = 19;
+
+const NAMES: [&str; 32] = [
+    "divide error",
+    "debug",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid tss",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved exception 15",
+    "x87 floating point exception",
+    "alignment check",
+    "machine check",
+    "simd floating point exception",
+    "virtualization exception",
+    "control protection exception",
+    "reserved exception 22",
+    "reserved exception 23",
+    "reserved exception 24",
+    "reserved exception 25",
+    "reserved exception 26",
+    "reserved exception 27",
+    "hypervisor injection exception",
+    "vmm communication exception",
+    "security exception",
+    "reserved exception 31",
+];
+
+// Every exception vector gets a stub that pushes a zero error code if the
+// cpu did not push one, then the vector, so all of them share the layout of
+// `ExceptionFrame`. The common part swaps in the kernel GS base if the
+// exception came from user mode and saves the general purpose registers.
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.macro ngos_exception_stub vector, error_code
+__ngos_exception_\vector:
+.if \error_code == 0
+    push 0
+.endif
+    push \vector
+    jmp __ngos_exception_common
+.endm
+
+.irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 9, 15, 16, 18, 19, 20, 22, 23, 24, 25, 26, 27, 28, 31
+    ngos_exception_stub \vector, 0
+.endr
+.irp vector, 8, 10, 11, 12, 13, 14, 17, 21, 29, 30
+    ngos_exception_stub \vector, 1
+.endr
+
+__ngos_exception_common:
+    test qword ptr [rsp + 24], 3
+    jz .Lexception_entry_kernel
+    swapgs
+.Lexception_entry_kernel:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call __ngos_exception_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    test qword ptr [rsp + 24], 3
+    jz .Lexception_exit_kernel
+    swapgs
+.Lexception_exit_kernel:
+    add rsp, 16
+    iretq
+
+.section .rodata
+.balign 8
+.global __ngos_exception_stubs
+__ngos_exception_stubs:
+.irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+    .quad __ngos_exception_\vector
+.endr
+.irp vector, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    .quad __ngos_exception_\vector
+.endr
+.text
+.att_syntax prefix
+"#
+);
+
+extern "C" {
+    static __ngos_exception_stubs: [u64; 32];
+}
+
+/// What the entry stubs leave on the stack, lowest address first.
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions without one.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+fn control_registers() -> [u64; 4] {
+    let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+    unsafe {
+        llvm_asm!("mov $0, cr0" : "=r"(cr0) ::: "intel");
+        llvm_asm!("mov $0, cr2" : "=r"(cr2) ::: "intel");
+        llvm_asm!("mov $0, cr3" : "=r"(cr3) ::: "intel");
+        llvm_asm!("mov $0, cr4" : "=r"(cr4) ::: "intel");
+    }
+    [cr0, cr2, cr3, cr4]
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [cr0, cr2, cr3, cr4] = control_registers();
+        writeln!(f, "error code: {:#x}", self.error_code)?;
+        let rows = [
+            [("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx), ("rdx", self.rdx)],
+            [("rsi", self.rsi), ("rdi", self.rdi), ("rbp", self.rbp), ("rsp", self.rsp)],
+            [("r8", self.r8), ("r9", self.r9), ("r10", self.r10), ("r11", self.r11)],
+            [("r12", self.r12), ("r13", self.r13), ("r14", self.r14), ("r15", self.r15)],
+            [("rip", self.rip), ("cs", self.cs), ("rflags", self.rflags), ("ss", self.ss)],
+            [("cr0", cr0), ("cr2", cr2), ("cr3", cr3), ("cr4", cr4)],
+        ];
+        for row in rows.iter() {
+            for (i, (name, value)) in row.iter().enumerate() {
+                let sep = if i == 0 { "" } else { " " };
+                write!(f, "{}{:>6}={:016x}", sep, name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Exit code of a user thread killed by exception `vector`, None for those
+/// that mean the kernel or the machine is in trouble whoever runs.
+fn user_exit_code(vector: u8) -> Option<i32> {
+    match vector {
+        DEBUG => Some(sched::EXIT_TRAP),
+        DIVIDE_ERROR | X87_FLOATING_POINT | SIMD_FLOATING_POINT => Some(sched::EXIT_FPE),
+        INVALID_OPCODE | DEVICE_NOT_AVAILABLE => Some(sched::EXIT_ILL),
+        ALIGNMENT_CHECK => Some(sched::EXIT_BUS),
+        OVERFLOW | BOUND_RANGE_EXCEEDED | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT
+        | GENERAL_PROTECTION_FAULT | PAGE_FAULT => Some(sched::EXIT_SEGV),
+        _ => None,
+    }
+}
+
+#[no_mangle]
+extern "C" fn __ngos_exception_dispatch(frame: &mut ExceptionFrame) {
+    let int = InterruptContextHandle::new(ContextKind::Exception);
+    let vector = frame.vector as u8;
+    let name = NAMES[vector as usize];
+
+    let mut page_fault = None;
+    match vector {
+        BREAKPOINT => {
+            println!("BREAKPOINT\n{}", frame);
+            return;
+        }
+        PAGE_FAULT => {
+            let addr = Cr2::read();
+            let err = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            match memory::do_page_fault(addr, err) {
+                Ok(()) => return,
+                Err(fault) => page_fault = Some((fault, addr)),
+            }
+        }
+        _ => {}
+    }
+
+    match user_exit_code(vector) {
+        Some(code) if frame.from_user() => {
+            match page_fault {
+                Some((fault, addr)) => log::warn!(
+                    "user page fault ({:?}) at {:?}, killing thread\n{}",
+                    fault,
+                    addr,
+                    frame
+                ),
+                None => log::warn!("user {}, killing thread\n{}", name, frame),
+            }
+            drop(int);
+            sched::exit(code)
+        }
+        _ => match page_fault {
+            Some((fault, addr)) => panic!(
+                "EXCEPTION: PAGE FAULT {:?}\naddress: {:?}\n{}",
+                fault, addr, frame
+            ),
+            None => panic!("EXCEPTION: {} (vector {})\n{}", name, vector, frame),
+        },
+    }
+}
+
+/// Points all 32 exception vectors of `idt` at the entry stubs. The double
+/// fault gets its own stack, the kernel stack may be what overflowed.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    // the entries only differ in the type of handler they take, and the
+    // stubs deal with error codes themselves
+    let entries = idt as *mut InterruptDescriptorTable as *mut Entry<HandlerFunc>;
+    for vector in 0..32 {
+        unsafe {
+            let stub: HandlerFunc = core::mem::transmute(__ngos_exception_stubs[vector]);
+            let options = (*entries.add(vector)).set_handler_fn(stub);
+            if vector == DOUBLE_FAULT as usize {
+                options.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn breakpoint_returns() {
+    x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn user_invalid_opcode_kills_thread() {
+    let code = [0x0f, 0x0b]; // ud2
+    assert_eq!(
+        super::user::run_user_code("ud2", &code),
+        sched::EXIT_ILL
+    );
+}
+
+#[test_case]
+fn user_divide_error_kills_thread() {
+    let code = [
+        0x31, 0xc9, // xor ecx, ecx
+        0xf7, 0xf1, // div ecx
+    ];
+    assert_eq!(
+        super::user::run_user_code("div0", &code),
+        sched::EXIT_FPE
+    );
+}
+
+#[test_case]
+fn user_general_protection_fault_kills_thread() {
+    let code = [0xfa]; // cli
+    assert_eq!(
+        super::user::run_user_code("cli", &code),
+        sched::EXIT_SEGV
+    );
+}
@@ -1,11 +1,22 @@
+use super::memory::KernelStack;
+use super::smp;
 use crate::util::{constant::Constant, init_cell::InitCell};
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
 use x86_64::structures::gdt::*;
 use x86_64::structures::tss::*;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-static _TSS: InitCell<Constant<TaskStateSegment>> = InitCell::new();
+// present | user segment | writable, the x86_64 crate does not set the
+// writable bit, which `iretq` insists on for the stack segment
+const DATA_SEGMENT: u64 = (1 << 47) | (1 << 44) | (1 << 41);
+const DPL_RING_3: u64 = 3 << 45;
+
+// rsp0 is rewritten on every context switch. These are the boot cpu's, the
+// others allocate their own in `init_ap`
+static _TSS: InitCell<Constant<UnsafeCell<TaskStateSegment>>> = InitCell::new();
 static _GDT: InitCell<Constant<(GlobalDescriptorTable, Selectors)>> = InitCell::new();
 
 // lazy_static! {
@@ -27,35 +38,63 @@ fn make_tss_static() -> TaskStateSegment {
     tss
 }
 
+// the order matters for `sysret`: user data must directly precede user code
 fn make_gdt_static(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::UserSegment(DATA_SEGMENT));
+    let user_data_selector = gdt.add_entry(Descriptor::UserSegment(DATA_SEGMENT | DPL_RING_3));
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
     let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
     (
         gdt,
         Selectors {
             code_selector,
+            data_selector,
+            user_data_selector,
+            user_code_selector,
             tss_selector,
         },
     )
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+pub fn selectors() -> &'static Selectors {
+    &_GDT.1
+}
+
+/// Sets the stack the running cpu switches to when an interrupt arrives in
+/// ring 3.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe {
+        (*smp::current().tss()).privilege_stack_table[0] = top;
+    }
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
-    _TSS.init(Constant::from(make_tss_static()));
-    _GDT.init(Constant::from(make_gdt_static(_TSS.get())));
-    _GDT.0.load();
+    gdt.0.load();
     unsafe {
-        set_cs(_GDT.1.code_selector);
-        load_tss(_GDT.1.tss_selector);
+        set_cs(gdt.1.code_selector);
+        load_ss(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
     }
+}
+
+pub fn init() {
+    _TSS.init(Constant::from(UnsafeCell::new(make_tss_static())));
+    _GDT.init(Constant::from(make_gdt_static(unsafe { &*_TSS.get().get() })));
+    load(&_GDT);
+    smp::current().set_tss(_TSS.get().get());
 
     // GDT.0.load();
     // unsafe {
@@ -63,3 +102,15 @@ pub fn init() {
     //     load_tss(GDT.1.tss_selector);
     // }
 }
+
+/// Gives an application processor its own TSS, and so its own GDT. The
+/// selectors come out the same as on the boot cpu.
+pub fn init_ap() {
+    let double_fault_stack = Box::leak(Box::new(KernelStack::new(1)));
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack.top();
+    let tss: &'static UnsafeCell<TaskStateSegment> = Box::leak(Box::new(UnsafeCell::new(tss)));
+    let gdt = Box::leak(Box::new(make_gdt_static(unsafe { &*tss.get() })));
+    load(gdt);
+    smp::current().set_tss(tss.get());
+}
@@ -1,150 +1,218 @@
-use super::gdt;
-use crate::{kernel::time::timer_event_handler, util::mutex_int::MutexInt};
-use core::sync::atomic::{AtomicBool, Ordering};
+use super::{irqchip, smp};
+use crate::core_local;
+use core::cell::Cell;
+use core::fmt;
 use lazy_static::*;
-use pic8259_simple::ChainedPics;
+use x86_64::instructions::interrupts;
 use x86_64::structures::idt::*;
 
-static IS_INTERRUPT_CONTEXT: AtomicBool = AtomicBool::new(false);
-
-pub fn is_interrupt_context() -> bool {
-    IS_INTERRUPT_CONTEXT.load(Ordering::Relaxed)
+/// How deep the running cpu is in each kind of interrupt context. They nest:
+/// a fault in an IRQ handler counts once as each.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct InterruptDepth {
+    /// Device and inter-processor interrupt handlers.
+    pub hardirq: u32,
+    /// Fault and trap handlers.
+    pub exception: u32,
+    /// Deferred work run on the way out of hardware interrupts.
+    pub softirq: u32,
 }
 
-struct InterruptContextHandle {
-    _private: (),
-}
+impl InterruptDepth {
+    const fn zero() -> InterruptDepth {
+        InterruptDepth {
+            hardirq: 0,
+            exception: 0,
+            softirq: 0,
+        }
+    }
 
-impl InterruptContextHandle {
-    fn new() -> Self {
-        IS_INTERRUPT_CONTEXT.store(true, Ordering::Relaxed);
-        Self { _private: () }
+    pub fn is_zero(&self) -> bool {
+        *self == InterruptDepth::zero()
     }
 }
 
-impl Drop for InterruptContextHandle {
-    fn drop(&mut self) {
-        IS_INTERRUPT_CONTEXT.store(false, Ordering::Relaxed);
+impl fmt::Display for InterruptDepth {
+    /// The non-zero depths, like `hardirq:1 exception:1`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let depths = [
+            ("hardirq", self.hardirq),
+            ("exception", self.exception),
+            ("softirq", self.softirq),
+        ];
+        let mut sep = "";
+        for &(name, depth) in depths.iter().filter(|(_, depth)| *depth > 0) {
+            write!(f, "{}{}:{}", sep, name, depth)?;
+            sep = " ";
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+core_local! {
+    static DEPTH: Cell<InterruptDepth> = Cell::new(InterruptDepth::zero());
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-    fn as_usize(self) -> usize {
-        self as usize
-    }
+/// Interrupt context nesting of the running cpu.
+pub fn interrupt_depth() -> InterruptDepth {
+    DEPTH.with(|depth| depth.get())
 }
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = make_idt_static();
+/// Whether the running cpu is in any interrupt context, so must not sleep.
+pub fn is_interrupt_context() -> bool {
+    !interrupt_depth().is_zero()
 }
 
-fn make_idt_static() -> InterruptDescriptorTable {
-    let mut idt = InterruptDescriptorTable::new();
-    unsafe {
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault
-            .set_handler_fn(double_fault_handler)
-            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+/// Swaps the kernel GS base in for a handler that interrupted user mode, and
+/// the user's back on the way out. Must come first in every handler that
+/// touches cpu-local data and outlive everything else in it.
+pub struct KernelGs {
+    from_user: bool,
+}
 
-        idt
+impl KernelGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> KernelGs {
+        let from_user = stack_frame.code_segment & 3 == 3;
+        if from_user {
+            unsafe { llvm_asm!("swapgs" :::: "volatile") };
+        }
+        KernelGs { from_user }
     }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    let _int = InterruptContextHandle::new();
-    println!("BREAKPOINT\n{:#?}", stack_frame);
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.from_user {
+            // an interrupt between here and `iretq` would find the user GS
+            interrupts::disable();
+            unsafe { llvm_asm!("swapgs" :::: "volatile") };
+        }
+    }
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    let _int = InterruptContextHandle::new();
-    panic!("DOUBLE FAULT\n{:#?}", stack_frame);
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ContextKind {
+    HardIrq,
+    Exception,
+    SoftIrq,
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    err: PageFaultErrorCode,
-) {
-    let _int = InterruptContextHandle::new();
-    use x86_64::registers::control::Cr2;
-    super::memory::do_page_fault(Cr2::read(), stack_frame, err);
+fn update_depth(kind: ContextKind, f: impl FnOnce(u32) -> u32) {
+    DEPTH.with(|cell| {
+        let mut depth = cell.get();
+        let count = match kind {
+            ContextKind::HardIrq => &mut depth.hardirq,
+            ContextKind::Exception => &mut depth.exception,
+            ContextKind::SoftIrq => &mut depth.softirq,
+        };
+        *count = f(*count);
+        cell.set(depth);
+    });
+}
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", err);
-    println!("{:#?}", stack_frame);
+/// Counts the running cpu as one level deeper in `kind` while it lives.
+pub struct InterruptContextHandle {
+    kind: ContextKind,
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+impl InterruptContextHandle {
+    pub fn new(kind: ContextKind) -> Self {
+        update_depth(kind, |count| count + 1);
+        Self { kind }
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    let _int = InterruptContextHandle::new();
-    timer_event_handler();
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+impl Drop for InterruptContextHandle {
+    fn drop(&mut self) {
+        update_depth(self.kind, |count| {
+            assert!(count > 0, "unbalanced {:?} context", self.kind);
+            count - 1
+        });
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    let _int = InterruptContextHandle::new();
-    use pc_keyboard::{layouts, Keyboard, ScancodeSet1};
-    use x86_64::instructions::port::Port;
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = make_idt_static();
+}
 
-    fn make_keyboard_static() -> MutexInt<Keyboard<layouts::Us104Key, ScancodeSet1>> {
-        MutexInt::new(true, Keyboard::new(layouts::Us104Key, ScancodeSet1))
-    }
-    lazy_static! {
-        static ref KEYBOARD: MutexInt<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            make_keyboard_static();
-    }
+fn make_idt_static() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    unsafe {
+        super::exception::install(&mut idt);
+        super::irq::install(&mut idt);
+        idt[smp::TICK_VECTOR as usize].set_handler_fn(tick_ipi_handler);
+        idt[irqchip::LOCAL_TIMER_VECTOR as usize].set_handler_fn(local_timer_handler);
+        idt[irqchip::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+        idt
+    }
+}
 
+/// The timer tick, forwarded by the boot cpu to the others.
+extern "x86-interrupt" fn tick_ipi_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
     {
-        let mut keyboard = KEYBOARD.lock();
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                println!("key pressed {:?}", key);
-            }
+        let _int = InterruptContextHandle::new(ContextKind::HardIrq);
+        // without a one-shot timer, timers of this cpu expire on the tick
+        if super::time::needs_tick() {
+            super::softirq::raise_softirq(super::softirq::SoftIrq::Timer);
         }
+        super::sched::tick();
+        irqchip::local_eoi();
     }
+    super::softirq::do_softirq();
+    super::sched::preempt();
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+/// The local APIC timer went off, some timer of this cpu is due.
+extern "x86-interrupt" fn local_timer_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    {
+        let _int = InterruptContextHandle::new(ContextKind::HardIrq);
+        super::softirq::raise_softirq(super::softirq::SoftIrq::Timer);
+        irqchip::local_eoi();
     }
+    super::softirq::do_softirq();
+    super::sched::preempt();
 }
 
-const PIC_1_OFFSET: u8 = 32;
-const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-
-static PICS: MutexInt<ChainedPics> = MutexInt::new(true, unsafe {
-    ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
-});
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    // nothing was delivered, so there is nothing to acknowledge
+}
 
 pub fn init() {
     crate::call_stack!();
     IDT.load();
-    unsafe { PICS.lock().initialize() };
-    x86_64::instructions::interrupts::enable();
+    // lines stay masked until drivers register for them
+    irqchip::init_pic();
+    interrupts::enable();
+}
+
+/// Loads the IDT on an application processor, it is shared by all cpus.
+pub fn init_ap() {
+    IDT.load();
+}
+
+#[test_case]
+fn interrupt_context_nests() {
+    interrupts::without_interrupts(|| {
+        assert!(!is_interrupt_context());
+        let irq = InterruptContextHandle::new(ContextKind::HardIrq);
+        {
+            // a fault inside the IRQ handler
+            let _fault = InterruptContextHandle::new(ContextKind::Exception);
+            let depth = interrupt_depth();
+            assert_eq!((depth.hardirq, depth.exception, depth.softirq), (1, 1, 0));
+        }
+        assert!(is_interrupt_context());
+        assert_eq!(interrupt_depth().hardirq, 1);
+        drop(irq);
+        assert!(!is_interrupt_context());
+    });
+    let depth = InterruptDepth {
+        hardirq: 1,
+        exception: 1,
+        softirq: 0,
+    };
+    assert_eq!(alloc::format!("{}", depth), "hardirq:1 exception:1");
 }
@@ -0,0 +1,244 @@
+use super::int::{ContextKind, InterruptContextHandle, KernelGs};
+use super::irqchip;
+use crate::util::mutex_int::MutexInt;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use heapless::consts::U4;
+use heapless::Vec;
+use lazy_static::*;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+/// Number of interrupt lines drivers can hook, the legacy ISA IRQs.
+pub const NR_IRQS: usize = 16;
+
+/// After this many interrupts in a row nobody handled, the line is taken to
+/// be stuck and masked.
+const UNHANDLED_LIMIT: u32 = 1000;
+
+/// What a handler tells about an interrupt on a possibly shared line.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IrqReturn {
+    /// The device of the handler raised it and was serviced.
+    Handled,
+    /// Not for this handler, let the next one look.
+    NotMine,
+}
+
+/// Runs in hard interrupt context with interrupts off: it must not block,
+/// and must not register or unregister handlers.
+pub type IrqHandler = fn() -> IrqReturn;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IrqError {
+    NoSuchLine,
+    AlreadyRegistered,
+    /// The line is shared by as many handlers as it can take.
+    LineFull,
+    NotRegistered,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IrqStats {
+    /// Interrupts raised on the line.
+    pub count: u64,
+    /// Interrupts the controller reported that were never really raised.
+    pub spurious: u64,
+    /// Interrupts no handler claimed.
+    pub unhandled: u64,
+}
+
+struct IrqLine {
+    handlers: MutexInt<Vec<IrqHandler, U4>>,
+    count: AtomicU64,
+    spurious: AtomicU64,
+    unhandled: AtomicU64,
+    // unhandled ones since the last handled one
+    unhandled_run: AtomicU32,
+}
+
+impl Default for IrqLine {
+    fn default() -> IrqLine {
+        IrqLine {
+            handlers: MutexInt::new(true, Vec::new()),
+            count: AtomicU64::new(0),
+            spurious: AtomicU64::new(0),
+            unhandled: AtomicU64::new(0),
+            unhandled_run: AtomicU32::new(0),
+        }
+    }
+}
+
+lazy_static! {
+    static ref LINES: [IrqLine; NR_IRQS] = Default::default();
+}
+
+fn line(irq: u8) -> Result<&'static IrqLine, IrqError> {
+    LINES.get(irq as usize).ok_or(IrqError::NoSuchLine)
+}
+
+/// Adds `handler` to the handlers of `irq`, unmasking the line for the first
+/// one. Handlers of a shared line run in the order they were registered.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let line = line(irq)?;
+    let mut handlers = line.handlers.lock();
+    if handlers.iter().any(|&h| h as usize == handler as usize) {
+        return Err(IrqError::AlreadyRegistered);
+    }
+    handlers.push(handler).map_err(|_| IrqError::LineFull)?;
+    if handlers.len() == 1 {
+        line.unhandled_run.store(0, Ordering::Relaxed);
+        irqchip::unmask(irq);
+    }
+    Ok(())
+}
+
+/// Removes `handler` from `irq`, masking the line once nobody is left.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let line = line(irq)?;
+    let mut handlers = line.handlers.lock();
+    if !handlers.iter().any(|&h| h as usize == handler as usize) {
+        return Err(IrqError::NotRegistered);
+    }
+    // keep the registration order of the rest
+    *handlers = handlers
+        .iter()
+        .copied()
+        .filter(|&h| h as usize != handler as usize)
+        .collect();
+    if handlers.is_empty() {
+        irqchip::mask(irq);
+    }
+    Ok(())
+}
+
+pub fn irq_stats(irq: u8) -> Result<IrqStats, IrqError> {
+    let line = line(irq)?;
+    Ok(IrqStats {
+        count: line.count.load(Ordering::Relaxed),
+        spurious: line.spurious.load(Ordering::Relaxed),
+        unhandled: line.unhandled.load(Ordering::Relaxed),
+    })
+}
+
+/// Runs the handlers of `irq` and acknowledges it.
+fn handle(irq: u8) {
+    let line = &LINES[irq as usize];
+    if irqchip::is_spurious(irq) {
+        line.spurious.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    run_handlers(irq, line);
+    irqchip::eoi(irq);
+}
+
+fn run_handlers(irq: u8, line: &IrqLine) {
+    line.count.fetch_add(1, Ordering::Relaxed);
+    // run them unlocked, so a handler on one cpu does not hold up changes
+    let handlers = line.handlers.lock().clone();
+    let mut handled = false;
+    for handler in handlers.iter() {
+        handled |= handler() == IrqReturn::Handled;
+    }
+    if handled {
+        line.unhandled_run.store(0, Ordering::Relaxed);
+    } else {
+        line.unhandled.fetch_add(1, Ordering::Relaxed);
+        if line.unhandled_run.fetch_add(1, Ordering::Relaxed) + 1 == UNHANDLED_LIMIT {
+            log::warn!("irq {}: nobody cared {} times in a row, masking it", irq, UNHANDLED_LIMIT);
+            irqchip::mask(irq);
+        }
+    }
+}
+
+fn irq_entry(stack_frame: &InterruptStackFrame, irq: u8) {
+    let _gs = KernelGs::enter(stack_frame);
+    {
+        let _int = InterruptContextHandle::new(ContextKind::HardIrq);
+        handle(irq);
+    }
+    // softirqs run once out of hard interrupt context, switching threads
+    // only once out of any
+    super::softirq::do_softirq();
+    super::sched::preempt();
+}
+
+macro_rules! irq_entries {
+    ($($irq:expr => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: &mut InterruptStackFrame) {
+                irq_entry(stack_frame, $irq);
+            }
+        )*
+        const IRQ_ENTRIES: [HandlerFunc; NR_IRQS] = [$($name),*];
+    };
+}
+
+irq_entries! {
+    0 => irq_entry_0,
+    1 => irq_entry_1,
+    2 => irq_entry_2,
+    3 => irq_entry_3,
+    4 => irq_entry_4,
+    5 => irq_entry_5,
+    6 => irq_entry_6,
+    7 => irq_entry_7,
+    8 => irq_entry_8,
+    9 => irq_entry_9,
+    10 => irq_entry_10,
+    11 => irq_entry_11,
+    12 => irq_entry_12,
+    13 => irq_entry_13,
+    14 => irq_entry_14,
+    15 => irq_entry_15,
+}
+
+/// Points the vectors of every line at the dispatcher.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    for (irq, &entry) in IRQ_ENTRIES.iter().enumerate() {
+        idt[irqchip::IRQ_BASE as usize + irq].set_handler_fn(entry);
+    }
+}
+
+#[cfg(test)]
+use core::sync::atomic::AtomicUsize;
+
+#[cfg(test)]
+static TEST_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+fn test_not_mine() -> IrqReturn {
+    TEST_CALLS.fetch_add(1, Ordering::SeqCst);
+    IrqReturn::NotMine
+}
+
+#[cfg(test)]
+fn test_handled() -> IrqReturn {
+    TEST_CALLS.fetch_add(10, Ordering::SeqCst);
+    IrqReturn::Handled
+}
+
+#[test_case]
+fn shared_irq_handlers_chain() {
+    // parallel port, nothing in the kernel uses it
+    let irq = 7;
+    assert_eq!(register_irq(irq, test_not_mine), Ok(()));
+    assert_eq!(register_irq(irq, test_not_mine), Err(IrqError::AlreadyRegistered));
+    assert_eq!(register_irq(irq, test_handled), Ok(()));
+    assert!(!irqchip::is_masked(irq));
+
+    let before = irq_stats(irq).unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _int = InterruptContextHandle::new(ContextKind::HardIrq);
+        run_handlers(irq, &LINES[irq as usize]);
+    });
+    // both ran, and the second one claimed it
+    assert_eq!(TEST_CALLS.load(Ordering::SeqCst), 11);
+    let after = irq_stats(irq).unwrap();
+    assert_eq!(after.count, before.count + 1);
+    assert_eq!(after.unhandled, before.unhandled);
+
+    assert_eq!(unregister_irq(irq, test_not_mine), Ok(()));
+    assert_eq!(unregister_irq(irq, test_not_mine), Err(IrqError::NotRegistered));
+    assert_eq!(unregister_irq(irq, test_handled), Ok(()));
+    assert!(irqchip::is_masked(irq));
+    assert_eq!(register_irq(NR_IRQS as u8, test_handled), Err(IrqError::NoSuchLine));
+}
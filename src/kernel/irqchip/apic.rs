@@ -0,0 +1,387 @@
+use super::{InterruptController, IRQ_BASE, SPURIOUS_VECTOR};
+use crate::kernel::memory::map_mmio;
+use heapless::consts::{U16, U8};
+use heapless::Vec;
+use x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_NMI: u32 = 0b100 << 8;
+
+// the timer counts down at the bus clock divided by 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// An I/O APIC and the global system interrupts it serves from `gsi_base` on.
+#[derive(Copy, Clone, Debug)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// A legacy IRQ that is not wired to the GSI of the same number.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IsaOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// Where the APICs are and how legacy IRQs reach them, as described by the
+/// ACPI MADT.
+#[derive(Clone, Debug)]
+pub struct ApicConfig {
+    pub local_apic: PhysAddr,
+    pub io_apics: Vec<IoApicInfo, U8>,
+    pub overrides: Vec<IsaOverride, U16>,
+}
+
+impl ApicConfig {
+    /// The usual PC layout: one I/O APIC at its default address with the PIT
+    /// moved to GSI 2. Used when the firmware does not describe the system.
+    pub fn legacy() -> ApicConfig {
+        let mut io_apics = Vec::new();
+        io_apics
+            .push(IoApicInfo {
+                id: 0,
+                address: PhysAddr::new(0xfec0_0000),
+                gsi_base: 0,
+            })
+            .unwrap();
+        let mut overrides = Vec::new();
+        overrides
+            .push(IsaOverride {
+                irq: 0,
+                gsi: 2,
+                active_low: false,
+                level_triggered: false,
+            })
+            .unwrap();
+        ApicConfig {
+            local_apic: local_apic_base(),
+            io_apics,
+            overrides,
+        }
+    }
+
+    /// How legacy `irq` arrives: its GSI, polarity and trigger mode. ISA
+    /// lines default to edge triggered, active high. `None` if there is no
+    /// line for it: its GSI was taken over by another IRQ, like GSI 2 by the
+    /// PIT.
+    pub fn resolve(&self, irq: u8) -> Option<IsaOverride> {
+        if let Some(line) = self.overrides.iter().find(|o| o.irq == irq) {
+            return Some(*line);
+        }
+        if self.overrides.iter().any(|o| o.gsi == irq as u32) {
+            return None;
+        }
+        Some(IsaOverride {
+            irq,
+            gsi: irq as u32,
+            active_low: false,
+            level_triggered: false,
+        })
+    }
+}
+
+/// Who an inter-processor interrupt goes to.
+#[derive(Copy, Clone, Debug)]
+pub enum IpiTarget {
+    Apic(u32),
+    AllButSelf,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Ipi {
+    /// Puts the target into wait-for-SIPI state.
+    Init,
+    /// Starts the target in real mode at `page << 12`.
+    Startup(u8),
+    Fixed(u8),
+}
+
+/// Whether the cpu has a local APIC.
+pub fn is_present() -> bool {
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    features.edx & (1 << 9) != 0
+}
+
+fn local_apic_base() -> PhysAddr {
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    PhysAddr::new(base & 0xf_ffff_f000)
+}
+
+/// The local APIC of the running cpu. Every cpu sees its own at the same
+/// address.
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn map(address: PhysAddr) -> LocalApic {
+        LocalApic {
+            base: map_mmio(address, 0x400),
+        }
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base.as_u64() as usize + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base.as_u64() as usize + reg) as *mut u32, value) }
+    }
+
+    /// Enables the local APIC of the running cpu: software enable with the
+    /// spurious vector, all priorities accepted, LINT0 masked since the PIC
+    /// is out of the picture, LINT1 delivering NMIs.
+    pub fn enable(&self) {
+        unsafe {
+            let mut msr = Msr::new(IA32_APIC_BASE);
+            let base = msr.read();
+            msr.write(base | APIC_BASE_ENABLE);
+        }
+        self.write(LAPIC_TPR, 0);
+        self.write(LAPIC_LVT_LINT0, LVT_MASKED);
+        self.write(LAPIC_LVT_LINT1, LVT_NMI);
+        self.write(LAPIC_LVT_ERROR, LVT_MASKED);
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        self.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    pub fn eoi(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+
+    /// Starts the timer counting down from `count`, raising `vector` when it
+    /// reaches zero unless `vector` is None. Replaces what was counting.
+    pub fn start_timer(&self, vector: Option<u8>, count: u32) {
+        self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        // one-shot mode is 0 in the mode bits
+        self.write(LAPIC_LVT_TIMER, vector.map_or(LVT_MASKED, |vector| vector as u32));
+        self.write(LAPIC_TIMER_INITIAL, count);
+    }
+
+    /// What is left to count down, 0 once it went off.
+    pub fn timer_count(&self) -> u32 {
+        self.read(LAPIC_TIMER_CURRENT)
+    }
+
+    pub fn send_ipi(&self, target: IpiTarget, ipi: Ipi) {
+        while self.read(LAPIC_ICR_LOW) & ICR_PENDING != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        let mut command = match ipi {
+            Ipi::Init => ICR_INIT | ICR_ASSERT,
+            Ipi::Startup(page) => ICR_STARTUP | ICR_ASSERT | page as u32,
+            Ipi::Fixed(vector) => ICR_ASSERT | vector as u32,
+        };
+        match target {
+            IpiTarget::Apic(id) => self.write(LAPIC_ICR_HIGH, id << 24),
+            IpiTarget::AllButSelf => command |= ICR_ALL_BUT_SELF,
+        }
+        // writing the low half sends it
+        self.write(LAPIC_ICR_LOW, command);
+    }
+}
+
+struct IoApic {
+    info: IoApicInfo,
+    base: VirtAddr,
+    entries: u32,
+}
+
+impl IoApic {
+    fn map(info: IoApicInfo) -> IoApic {
+        let mut io_apic = IoApic {
+            info,
+            base: map_mmio(info.address, 0x20),
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile(self.base.as_mut_ptr::<u32>(), reg);
+            core::ptr::read_volatile((self.base + 0x10u64).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile(self.base.as_mut_ptr::<u32>(), reg);
+            core::ptr::write_volatile((self.base + 0x10u64).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn serves(&self, gsi: u32) -> bool {
+        self.info.gsi_base <= gsi && gsi < self.info.gsi_base + self.entries
+    }
+
+    fn read_entry(&self, gsi: u32) -> u64 {
+        let reg = IOAPIC_REDIRECTION + 2 * (gsi - self.info.gsi_base);
+        (self.read(reg + 1) as u64) << 32 | self.read(reg) as u64
+    }
+
+    fn write_entry(&mut self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION + 2 * (gsi - self.info.gsi_base);
+        // keep the entry masked while it is half written
+        self.write(reg, REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+/// Local APIC plus I/O APICs. Legacy IRQs are routed as fixed interrupts to
+/// the boot cpu.
+pub struct Apic {
+    config: ApicConfig,
+    local: LocalApic,
+    io_apics: Vec<IoApic, U8>,
+}
+
+impl Apic {
+    pub fn new(config: ApicConfig) -> Apic {
+        let local = LocalApic::map(config.local_apic);
+        local.enable();
+        let mut io_apics = Vec::new();
+        for &info in config.io_apics.iter() {
+            let io_apic = IoApic::map(info);
+            log::info!(
+                "io apic {} at {:?}: gsi {}..{}",
+                info.id,
+                info.address,
+                info.gsi_base,
+                info.gsi_base + io_apic.entries
+            );
+            if io_apics.push(io_apic).is_err() {
+                log::warn!("too many io apics, ignoring {}", info.id);
+            }
+        }
+
+        let mut apic = Apic {
+            config,
+            local,
+            io_apics,
+        };
+        // every gsi starts masked, legacy lines get their vectors
+        for io_apic in apic.io_apics.iter_mut() {
+            for gsi in io_apic.info.gsi_base..io_apic.info.gsi_base + io_apic.entries {
+                io_apic.write_entry(gsi, REDIRECTION_MASKED);
+            }
+        }
+        let boot_cpu = apic.local.id();
+        for irq in 0..16 {
+            apic.route(irq, boot_cpu);
+        }
+        apic
+    }
+
+    pub fn local(&self) -> &LocalApic {
+        &self.local
+    }
+
+    fn io_apic_for(&mut self, gsi: u32) -> Option<&mut IoApic> {
+        self.io_apics.iter_mut().find(|io_apic| io_apic.serves(gsi))
+    }
+
+    fn route(&mut self, irq: u8, cpu: u8) {
+        let line = match self.config.resolve(irq) {
+            Some(line) => line,
+            None => return,
+        };
+        let mut entry = (IRQ_BASE + irq) as u64 | REDIRECTION_MASKED | (cpu as u64) << 56;
+        if line.active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if line.level_triggered {
+            entry |= REDIRECTION_LEVEL;
+        }
+        match self.io_apic_for(line.gsi) {
+            Some(io_apic) => io_apic.write_entry(line.gsi, entry),
+            None => log::warn!("irq {} (gsi {}) has no io apic", irq, line.gsi),
+        }
+    }
+}
+
+impl InterruptController for Apic {
+    fn name(&self) -> &'static str {
+        "apic"
+    }
+
+    fn set_masked(&mut self, irq: u8, masked: bool) {
+        let gsi = match self.config.resolve(irq) {
+            Some(line) => line.gsi,
+            None => {
+                log::warn!("irq {} has no line of its own", irq);
+                return;
+            }
+        };
+        let io_apic = match self.io_apic_for(gsi) {
+            Some(io_apic) => io_apic,
+            None => {
+                log::warn!("irq {} (gsi {}) has no io apic", irq, gsi);
+                return;
+            }
+        };
+        let entry = io_apic.read_entry(gsi);
+        let entry = if masked {
+            entry | REDIRECTION_MASKED
+        } else {
+            entry & !REDIRECTION_MASKED
+        };
+        io_apic.write_entry(gsi, entry);
+    }
+
+    fn is_masked(&self, irq: u8) -> bool {
+        let gsi = match self.config.resolve(irq) {
+            Some(line) => line.gsi,
+            None => return true,
+        };
+        self.io_apics
+            .iter()
+            .find(|io_apic| io_apic.serves(gsi))
+            .map_or(true, |io_apic| io_apic.read_entry(gsi) & REDIRECTION_MASKED != 0)
+    }
+
+    fn eoi(&mut self, _irq: u8) {
+        self.local.eoi();
+    }
+}
@@ -0,0 +1,205 @@
+mod apic;
+mod pic;
+
+use crate::util::mutex_int::MutexInt;
+pub use apic::{ApicConfig, IoApicInfo, Ipi, IpiTarget, IsaOverride};
+use apic::Apic;
+use pic::Pic;
+
+/// Vector of legacy IRQ 0, the rest follow in order.
+pub const IRQ_BASE: u8 = 32;
+/// Vector the local APIC raises for interrupts that went away before being
+/// delivered. They must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// Vector of the local APIC timer.
+pub const LOCAL_TIMER_VECTOR: u8 = 0xec;
+
+/// Routes legacy IRQs to `IRQ_BASE + irq` on the boot cpu.
+pub trait InterruptController: Send {
+    fn name(&self) -> &'static str;
+    fn set_masked(&mut self, irq: u8, masked: bool);
+    fn is_masked(&self, irq: u8) -> bool;
+    /// Acknowledges `irq` so the controller delivers the next one.
+    fn eoi(&mut self, irq: u8);
+    /// Whether `irq` went away before it was delivered. A spurious one is
+    /// not to be acknowledged with `eoi`, anything else it needs is done.
+    fn is_spurious(&mut self, _irq: u8) -> bool {
+        false
+    }
+}
+
+enum Chip {
+    Pic(Pic),
+    Apic(Apic),
+}
+
+impl Chip {
+    fn get(&mut self) -> &mut dyn InterruptController {
+        match self {
+            Chip::Pic(pic) => pic,
+            Chip::Apic(apic) => apic,
+        }
+    }
+}
+
+static CHIP: MutexInt<Chip> = MutexInt::new(true, Chip::Pic(Pic::new()));
+
+/// Brings up the 8259s so interrupts can be taken before memory management
+/// is ready to map the APICs.
+pub fn init_pic() {
+    crate::call_stack!();
+    if let Chip::Pic(pic) = &mut *CHIP.lock() {
+        pic.initialize();
+    }
+}
+
+/// Switches to the APICs described by `config` if the cpu has one, keeping
+/// the PIC otherwise. Lines unmasked on the PIC stay unmasked.
+pub fn init(config: ApicConfig) {
+    crate::call_stack!();
+    if !apic::is_present() {
+        log::info!("no local apic, staying with the 8259 pic");
+        return;
+    }
+    let mut chip = CHIP.lock();
+    let pic = match &mut *chip {
+        Chip::Pic(pic) => pic,
+        Chip::Apic(_) => return,
+    };
+    let unmasked: u16 = (0..16)
+        .filter(|&irq| irq != 2 && !pic.is_masked(irq))
+        .fold(0, |lines, irq| lines | (1 << irq));
+    pic.disable();
+
+    let mut apic = Apic::new(config);
+    for irq in (0..16).filter(|irq| unmasked & (1 << irq) != 0) {
+        apic.set_masked(irq, false);
+    }
+    log::info!("local apic {} enabled", apic.local().id());
+    *chip = Chip::Apic(apic);
+}
+
+pub fn mask(irq: u8) {
+    CHIP.lock().get().set_masked(irq, true);
+}
+
+pub fn unmask(irq: u8) {
+    CHIP.lock().get().set_masked(irq, false);
+}
+
+pub fn is_masked(irq: u8) -> bool {
+    CHIP.lock().get().is_masked(irq)
+}
+
+pub fn eoi(irq: u8) {
+    CHIP.lock().get().eoi(irq);
+}
+
+pub fn is_spurious(irq: u8) -> bool {
+    CHIP.lock().get().is_spurious(irq)
+}
+
+pub fn controller_name() -> &'static str {
+    CHIP.lock().get().name()
+}
+
+/// Enables the local APIC of an application processor, once the boot cpu
+/// has switched to the APICs.
+pub fn init_ap() {
+    if let Chip::Apic(apic) = &*CHIP.lock() {
+        apic.local().enable();
+    }
+}
+
+/// ID of the running cpu's local APIC, None while on the PIC.
+pub fn local_apic_id() -> Option<u32> {
+    match &*CHIP.lock() {
+        Chip::Apic(apic) => Some(apic.local().id() as u32),
+        Chip::Pic(_) => None,
+    }
+}
+
+pub fn send_ipi(target: IpiTarget, ipi: Ipi) {
+    match &*CHIP.lock() {
+        Chip::Apic(apic) => apic.local().send_ipi(target, ipi),
+        Chip::Pic(_) => panic!("no local apic to send ipis with"),
+    }
+}
+
+/// Starts the local APIC timer of the running cpu counting down from
+/// `count`, raising `LOCAL_TIMER_VECTOR` at zero if `interrupt`. Returns
+/// false while on the PIC, which has no timer.
+pub fn start_local_timer(count: u32, interrupt: bool) -> bool {
+    match &*CHIP.lock() {
+        Chip::Apic(apic) => {
+            let vector = if interrupt { Some(LOCAL_TIMER_VECTOR) } else { None };
+            apic.local().start_timer(vector, count);
+            true
+        }
+        Chip::Pic(_) => false,
+    }
+}
+
+/// What the local APIC timer of the running cpu has left to count.
+pub fn local_timer_count() -> Option<u32> {
+    match &*CHIP.lock() {
+        Chip::Apic(apic) => Some(apic.local().timer_count()),
+        Chip::Pic(_) => None,
+    }
+}
+
+/// Acknowledges an interrupt raised by the local APIC: inter-processor
+/// interrupts and its timer.
+pub fn local_eoi() {
+    if let Chip::Apic(apic) = &*CHIP.lock() {
+        apic.local().eoi();
+    }
+}
+
+#[test_case]
+fn isa_overrides_resolve() {
+    let mut config = ApicConfig::legacy();
+    config
+        .overrides
+        .push(IsaOverride {
+            irq: 9,
+            gsi: 9,
+            active_low: true,
+            level_triggered: true,
+        })
+        .unwrap();
+    assert_eq!(config.resolve(0).unwrap().gsi, 2);
+    let sci = config.resolve(9).unwrap();
+    assert!(sci.active_low && sci.level_triggered);
+    let keyboard = config.resolve(1).unwrap();
+    assert_eq!(keyboard.gsi, 1);
+    assert!(!keyboard.active_low && !keyboard.level_triggered);
+}
+
+#[test_case]
+fn legacy_irqs_get_lines_of_their_own() {
+    let config = ApicConfig::legacy();
+    // the PIT took over the cascade line
+    assert_eq!(config.resolve(2), None);
+    let lines: heapless::Vec<IsaOverride, heapless::consts::U16> =
+        (0..16).filter_map(|irq| config.resolve(irq)).collect();
+    for (i, line) in lines.iter().enumerate() {
+        for other in lines[..i].iter() {
+            assert_ne!(line.gsi, other.gsi, "irqs {} and {} share a gsi", line.irq, other.irq);
+            assert_ne!(IRQ_BASE + line.irq, IRQ_BASE + other.irq);
+        }
+    }
+    assert_eq!(lines.len(), 15);
+}
+
+#[test_case]
+fn irq_mask_round_trip() {
+    // parallel port, nothing in the kernel uses it
+    let irq = 7;
+    assert!(is_masked(irq));
+    unmask(irq);
+    assert!(!is_masked(irq));
+    mask(irq);
+    assert!(is_masked(irq));
+    assert!(!is_masked(0), "timer masked on {}", controller_name());
+}
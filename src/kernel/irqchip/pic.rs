@@ -0,0 +1,103 @@
+use super::{InterruptController, IRQ_BASE};
+use pic8259_simple::ChainedPics;
+use x86_64::instructions::port::Port;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_2_DATA: u16 = 0xa1;
+
+const CMD_READ_ISR: u8 = 0x0b;
+const CMD_EOI: u8 = 0x20;
+
+/// The legacy pair of 8259s, remapped to `IRQ_BASE`.
+pub struct Pic {
+    pics: ChainedPics,
+}
+
+impl Pic {
+    pub const fn new() -> Pic {
+        Pic {
+            pics: unsafe { ChainedPics::new(IRQ_BASE, IRQ_BASE + 8) },
+        }
+    }
+
+    /// Remaps both chips away from the exception vectors, every line masked
+    /// but the cascade.
+    pub fn initialize(&mut self) {
+        unsafe {
+            self.pics.initialize();
+        }
+        self.write_masks(0xffff & !(1 << 2));
+    }
+
+    /// Masks every line, for when another controller takes over.
+    pub fn disable(&mut self) {
+        self.write_masks(0xffff);
+    }
+
+    fn read_masks(&self) -> u16 {
+        unsafe {
+            let low: u8 = Port::new(PIC_1_DATA).read();
+            let high: u8 = Port::new(PIC_2_DATA).read();
+            ((high as u16) << 8) | low as u16
+        }
+    }
+
+    fn in_service(&self) -> u16 {
+        unsafe {
+            let mut command_1: Port<u8> = Port::new(PIC_1_COMMAND);
+            let mut command_2: Port<u8> = Port::new(PIC_2_COMMAND);
+            command_1.write(CMD_READ_ISR);
+            command_2.write(CMD_READ_ISR);
+            ((command_2.read() as u16) << 8) | command_1.read() as u16
+        }
+    }
+
+    fn write_masks(&mut self, masks: u16) {
+        unsafe {
+            Port::new(PIC_1_DATA).write(masks as u8);
+            Port::new(PIC_2_DATA).write((masks >> 8) as u8);
+        }
+    }
+}
+
+impl InterruptController for Pic {
+    fn name(&self) -> &'static str {
+        "8259 pic"
+    }
+
+    fn set_masked(&mut self, irq: u8, masked: bool) {
+        assert!(irq < 16, "no such pic line");
+        let masks = self.read_masks();
+        let masks = if masked {
+            masks | (1 << irq)
+        } else {
+            masks & !(1 << irq)
+        };
+        self.write_masks(masks);
+    }
+
+    fn is_masked(&self, irq: u8) -> bool {
+        self.read_masks() & (1 << irq) != 0
+    }
+
+    fn eoi(&mut self, irq: u8) {
+        unsafe {
+            self.pics.notify_end_of_interrupt(IRQ_BASE + irq);
+        }
+    }
+
+    // a chip raises its lowest priority line when the real request went away
+    // during the handshake, then the line is not in service
+    fn is_spurious(&mut self, irq: u8) -> bool {
+        if (irq != 7 && irq != 15) || self.in_service() & (1 << irq) != 0 {
+            return false;
+        }
+        if irq == 15 {
+            // the master did see a real request on the cascade
+            unsafe { Port::<u8>::new(PIC_1_COMMAND).write(CMD_EOI) };
+        }
+        true
+    }
+}
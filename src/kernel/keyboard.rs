@@ -0,0 +1,35 @@
+use super::irq::{register_irq, IrqReturn};
+use crate::util::mutex_int::MutexInt;
+use lazy_static::*;
+use pc_keyboard::{layouts, Keyboard, ScancodeSet1};
+use x86_64::instructions::port::Port;
+
+const KEYBOARD_IRQ: u8 = 1;
+const DATA_PORT: u16 = 0x60;
+
+fn make_keyboard_static() -> MutexInt<Keyboard<layouts::Us104Key, ScancodeSet1>> {
+    MutexInt::new(true, Keyboard::new(layouts::Us104Key, ScancodeSet1))
+}
+
+lazy_static! {
+    static ref KEYBOARD: MutexInt<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        make_keyboard_static();
+}
+
+fn keyboard_irq() -> IrqReturn {
+    let mut port = Port::new(DATA_PORT);
+    let scancode: u8 = unsafe { port.read() };
+
+    let mut keyboard = KEYBOARD.lock();
+    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        if let Some(key) = keyboard.process_keyevent(key_event) {
+            println!("key pressed {:?}", key);
+        }
+    }
+    IrqReturn::Handled
+}
+
+pub fn init() {
+    crate::call_stack!();
+    register_irq(KEYBOARD_IRQ, keyboard_irq).expect("keyboard irq taken");
+}
@@ -0,0 +1,199 @@
+use core::ops::Range;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+// pages are always readable on x86, nothing checks for this one
+#[allow(dead_code)]
+pub const PF_R: u32 = 4;
+
+const PAGE_SIZE: u64 = 4096;
+
+pub const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+pub const PROGRAM_HEADER_SIZE: usize = core::mem::size_of::<ProgramHeader>();
+
+/// Reasons an image is refused before anything gets mapped.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaders,
+    SegmentOutOfFile,
+    SegmentOutOfUserSpace,
+    MisalignedSegment,
+    OverlappingSegments,
+    NoLoadableSegment,
+    EntryNotExecutable,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Header {
+    pub ident: [u8; 16],
+    pub e_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn vaddr_range(&self) -> Range<u64> {
+        self.vaddr..self.vaddr + self.memsz
+    }
+
+    /// `vaddr_range` widened to whole pages.
+    pub fn page_range(&self) -> Range<u64> {
+        let range = self.vaddr_range();
+        range.start & !(PAGE_SIZE - 1)..(range.end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+    }
+
+    pub fn file_range(&self) -> Range<usize> {
+        self.offset as usize..(self.offset + self.filesz) as usize
+    }
+}
+
+/// A validated ELF64 executable. Every loadable segment lies within the image
+/// and within `user_range`, so loading it cannot fail on malformed input.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: Header,
+}
+
+fn read<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(core::mem::size_of::<T>())?;
+    if end > data.len() {
+        return None;
+    }
+    Some(unsafe { core::ptr::read_unaligned(data[offset..].as_ptr() as *const T) })
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8], user_range: Range<u64>) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        let header: Header = read(data, 0).unwrap();
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if header.ident[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if header.ident[6] != EV_CURRENT || header.version != EV_CURRENT as u32 {
+            return Err(ElfError::BadVersion);
+        }
+        if header.e_type != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if header.machine != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+        if header.phentsize as usize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let table_end = (header.phnum as u64)
+            .checked_mul(PROGRAM_HEADER_SIZE as u64)
+            .and_then(|size| size.checked_add(header.phoff))
+            .ok_or(ElfError::BadProgramHeaders)?;
+        if table_end > data.len() as u64 {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        let elf = Elf { data, header };
+        let mut loadable = 0;
+        for (i, ph) in elf.segments().enumerate() {
+            let end = ph.vaddr.checked_add(ph.memsz);
+            let file_end = ph.offset.checked_add(ph.filesz);
+            if ph.filesz > ph.memsz || file_end.map_or(true, |end| end > data.len() as u64) {
+                return Err(ElfError::SegmentOutOfFile);
+            }
+            match end {
+                Some(end) if ph.vaddr >= user_range.start && end <= user_range.end => {}
+                _ => return Err(ElfError::SegmentOutOfUserSpace),
+            }
+            if ph.vaddr % PAGE_SIZE != ph.offset % PAGE_SIZE {
+                return Err(ElfError::MisalignedSegment);
+            }
+            // they may share a page, but not bytes
+            let overlaps = elf.segments().take(i).any(|other| {
+                let (a, b) = (ph.vaddr_range(), other.vaddr_range());
+                a.start < b.end && b.start < a.end
+            });
+            if overlaps {
+                return Err(ElfError::OverlappingSegments);
+            }
+            loadable += 1;
+        }
+        if loadable == 0 {
+            return Err(ElfError::NoLoadableSegment);
+        }
+        let entry_ok = elf
+            .segments()
+            .any(|ph| ph.flags & PF_X != 0 && ph.vaddr_range().contains(&header.entry));
+        if !entry_ok {
+            return Err(ElfError::EntryNotExecutable);
+        }
+        Ok(elf)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let phoff = self.header.phoff as usize;
+        (0..self.header.phnum as usize)
+            .map(move |i| read(data, phoff + i * PROGRAM_HEADER_SIZE).unwrap())
+    }
+
+    /// The `PT_LOAD` program headers, in file order.
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers().filter(|ph| ph.p_type == PT_LOAD)
+    }
+
+    /// Where the program headers end up in memory, if a segment maps them.
+    pub fn program_headers_addr(&self) -> Option<u64> {
+        let phoff = self.header.phoff;
+        self.segments()
+            .find(|ph| ph.offset <= phoff && phoff < ph.offset + ph.filesz)
+            .map(|ph| ph.vaddr + (phoff - ph.offset))
+    }
+}
@@ -0,0 +1,406 @@
+mod elf;
+
+pub use elf::ElfError;
+
+use super::memory::{user_virtual_range, AddressSpace, Backing, FileExtent, MapError};
+use super::sched::{self, JoinHandle};
+use super::user::enter_user_mode;
+use alloc::{sync::Arc, vec::Vec};
+use elf::{Elf, PF_W, PF_X, PROGRAM_HEADER_SIZE};
+use x86_64::{
+    structures::paging::{page::PageRange, Page, PageTableFlags},
+    VirtAddr,
+};
+
+/// Size of the initial user stack.
+pub const USER_STACK_PAGES: u64 = 16;
+/// How far the user stack may grow.
+pub const USER_STACK_LIMIT: u64 = 8 << 20;
+/// Upper bound on the argument and environment strings, so that they leave
+/// most of the initial stack to the program.
+pub const ARG_MAX: usize = (USER_STACK_PAGES as usize * 4096) / 4;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LoadError {
+    Elf(ElfError),
+    Map(MapError),
+    ArgumentsTooLong,
+    /// No frames left for the initial stack.
+    OutOfMemory,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+/// A program mapped into the user half, ready to enter.
+#[derive(Copy, Clone, Debug)]
+pub struct LoadedProgram {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+fn segment_flags(elf_flags: u32) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if elf_flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if elf_flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Adds a file backed area for every loadable segment. Pages are read in
+/// from `file` when first touched; whatever lies past the file contents,
+/// including .bss, reads as zero. A page shared by segments becomes an area
+/// of its own with the permissions of all of them, the rest of each segment
+/// keeps its own.
+fn map_segments(space: &AddressSpace, elf: &Elf, file: &Arc<[u8]>) -> Result<(), LoadError> {
+    let segments: Vec<_> = elf.segments().filter(|ph| ph.memsz > 0).collect();
+
+    // which segments cover a page only changes where one starts or ends
+    let mut bounds: Vec<u64> = segments
+        .iter()
+        .flat_map(|ph| {
+            let pages = ph.page_range();
+            core::iter::once(pages.start).chain(core::iter::once(pages.end))
+        })
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    for pages in bounds.windows(2).map(|pair| pair[0]..pair[1]) {
+        let covering = || {
+            segments.iter().filter(|ph| {
+                let range = ph.page_range();
+                range.start < pages.end && pages.start < range.end
+            })
+        };
+        let flags = covering().fold(0, |flags, ph| flags | ph.flags);
+        let extents: Vec<_> = covering()
+            .map(|ph| FileExtent {
+                addr: ph.vaddr,
+                offset: ph.offset,
+                len: ph.filesz,
+            })
+            .collect();
+        if extents.is_empty() {
+            continue;
+        }
+        let range = PageRange {
+            start: Page::containing_address(VirtAddr::new(pages.start)),
+            end: Page::containing_address(VirtAddr::new(pages.end)),
+        };
+        let backing = Backing::File {
+            file: file.clone(),
+            extents,
+        };
+        space
+            .map_fixed(range, segment_flags(flags), backing)
+            .map_err(LoadError::Map)?;
+    }
+    Ok(())
+}
+
+/// Lays out the System V initial stack below `stack_top`: argc, the argv and
+/// envp pointer arrays, the auxiliary vector, and the strings above them.
+/// Returns the stack pointer to enter with, 16 byte aligned.
+fn setup_stack(
+    space: &AddressSpace,
+    stack_top: u64,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<u64, LoadError> {
+    let word_count = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * (auxv.len() + 1);
+    let strings_size: usize = argv.iter().chain(envp.iter()).map(|s| s.len() + 1).sum();
+    let sp = (stack_top - (word_count * 8 + strings_size) as u64) & !0xf;
+
+    // the strings go right after the words, in the same order
+    let mut block: Vec<u8> = Vec::with_capacity((stack_top - sp) as usize);
+    let mut string_addr = sp + word_count as u64 * 8;
+    fn push_word(block: &mut Vec<u8>, word: u64) {
+        block.extend_from_slice(&word.to_le_bytes());
+    }
+    push_word(&mut block, argv.len() as u64);
+    for strings in [argv, envp].iter() {
+        for s in strings.iter() {
+            push_word(&mut block, string_addr);
+            string_addr += s.len() as u64 + 1;
+        }
+        push_word(&mut block, 0);
+    }
+    for &(key, value) in auxv.iter().chain(core::iter::once(&(AT_NULL, 0))) {
+        push_word(&mut block, key);
+        push_word(&mut block, value);
+    }
+    for s in argv.iter().chain(envp.iter()) {
+        block.extend_from_slice(s.as_bytes());
+        block.push(0);
+    }
+
+    // the stack area was just added, so only frames can run out
+    space
+        .copy_to_user(VirtAddr::new(sp), &block)
+        .map_err(|_| LoadError::OutOfMemory)?;
+    Ok(sp)
+}
+
+/// Validates `image` and maps it into the user half of `space` together with
+/// a fresh stack holding `argv` and `envp`.
+pub fn load(
+    space: &AddressSpace,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<LoadedProgram, LoadError> {
+    let strings: usize = argv.iter().chain(envp.iter()).map(|s| s.len() + 1).sum();
+    let pointers = (argv.len() + envp.len() + 3) * 8;
+    if strings + pointers > ARG_MAX {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+
+    let file: Arc<[u8]> = Arc::from(image);
+    let elf = Elf::parse(&file, user_virtual_range())?;
+    map_segments(space, &elf, &file)?;
+
+    let header = elf.header();
+    let mut auxv = Vec::new();
+    if let Some(phdr) = elf.program_headers_addr() {
+        auxv.push((AT_PHDR, phdr));
+    }
+    auxv.push((AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+    auxv.push((AT_PHNUM, header.phnum as u64));
+    auxv.push((AT_PAGESZ, 4096));
+    auxv.push((AT_ENTRY, header.entry));
+
+    let stack = space
+        .map_anywhere(
+            USER_STACK_PAGES,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            Backing::Stack {
+                limit: USER_STACK_LIMIT,
+            },
+        )
+        .map_err(LoadError::Map)?;
+    let stack_top = stack.end.start_address().as_u64();
+    let sp = setup_stack(space, stack_top, argv, envp, &auxv)?;
+    Ok(LoadedProgram {
+        entry: VirtAddr::new(header.entry),
+        stack_pointer: VirtAddr::new(sp),
+    })
+}
+
+/// Replaces the current thread with the program in `image`, in a fresh
+/// address space. Only returns if the image cannot be loaded.
+pub fn exec(image: &[u8], argv: &[&str], envp: &[&str]) -> LoadError {
+    let space = Arc::new(AddressSpace::new());
+    match load(&space, image, argv, envp) {
+        Ok(program) => {
+            sched::set_addr_space(space);
+            enter_user_mode(program.entry, program.stack_pointer)
+        }
+        Err(err) => err,
+    }
+}
+
+/// Loads `image` into a fresh address space and runs it on a new thread.
+/// Load errors are reported here, before any thread is created.
+pub fn spawn_program(
+    name: &'static str,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<JoinHandle, LoadError> {
+    let space = Arc::new(AddressSpace::new());
+    let program = load(&space, image, argv, envp)?;
+    log::trace!("loaded {} with entry {:?}", name, program.entry);
+    Ok(sched::spawn_in(name, space, move || {
+        enter_user_mode(program.entry, program.stack_pointer)
+    }))
+}
+
+/// Builds a minimal executable: one read+execute segment at `base` holding
+/// the headers followed by `code`, with `bss` zero bytes after it.
+#[cfg(test)]
+fn build_elf(base: u64, code: &[u8], bss: u64) -> Vec<u8> {
+    use elf::{HEADER_SIZE, PF_R};
+
+    let code_offset = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64;
+    let filesz = code_offset + code.len() as u64;
+    let segment = (PF_R | PF_X, 0, base, filesz, filesz + bss);
+    build_elf_with(base + code_offset, &[segment], code)
+}
+
+/// Builds an executable with `segments`, given as (flags, offset, vaddr,
+/// filesz, memsz), from the headers followed by `body`.
+#[cfg(test)]
+fn build_elf_with(entry: u64, segments: &[(u32, u64, u64, u64, u64)], body: &[u8]) -> Vec<u8> {
+    use elf::{HEADER_SIZE, PT_LOAD};
+
+    let mut image = Vec::new();
+    image.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    image.extend_from_slice(&[0; 8]);
+    image.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    image.extend_from_slice(&0x3eu16.to_le_bytes()); // x86-64
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&entry.to_le_bytes());
+    image.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes()); // phoff
+    image.extend_from_slice(&0u64.to_le_bytes()); // shoff
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&(segments.len() as u16).to_le_bytes()); // phnum
+    image.extend_from_slice(&[0; 6]);
+
+    for &(flags, offset, vaddr, filesz, memsz) in segments {
+        image.extend_from_slice(&PT_LOAD.to_le_bytes());
+        image.extend_from_slice(&flags.to_le_bytes());
+        image.extend_from_slice(&offset.to_le_bytes());
+        image.extend_from_slice(&vaddr.to_le_bytes());
+        image.extend_from_slice(&vaddr.to_le_bytes()); // paddr
+        image.extend_from_slice(&filesz.to_le_bytes());
+        image.extend_from_slice(&memsz.to_le_bytes());
+        image.extend_from_slice(&4096u64.to_le_bytes());
+    }
+
+    image.extend_from_slice(body);
+    image
+}
+
+#[cfg(test)]
+const TEST_IMAGE_BASE: u64 = 0x1000_0000;
+
+#[test_case]
+fn elf_rejects_malformed_images() {
+    let base = user_virtual_range().start + TEST_IMAGE_BASE;
+    let code = [0x0f, 0x05];
+    let good = build_elf(base, &code, 0);
+    let reject = |image: &[u8]| Elf::parse(image, user_virtual_range()).err();
+
+    assert!(reject(&good).is_none());
+    assert_eq!(reject(&good[..10]), Some(ElfError::TooShort));
+
+    let mut bad = good.clone();
+    bad[1] = b'X';
+    assert_eq!(reject(&bad), Some(ElfError::BadMagic));
+
+    let mut bad = good.clone();
+    bad[4] = 1;
+    assert_eq!(reject(&bad), Some(ElfError::NotElf64));
+
+    let mut bad = good.clone();
+    bad[18] = 0x03; // i386
+    assert_eq!(reject(&bad), Some(ElfError::WrongMachine));
+
+    let mut bad = good.clone();
+    bad[56] = 200; // phnum runs past the end of the image
+    assert_eq!(reject(&bad), Some(ElfError::BadProgramHeaders));
+
+    // the segment claims more file bytes than there are
+    let mut bad = good.clone();
+    bad[64 + 32..64 + 40].copy_from_slice(&0x10000u64.to_le_bytes());
+    bad[64 + 40..64 + 48].copy_from_slice(&0x10000u64.to_le_bytes());
+    assert_eq!(reject(&bad), Some(ElfError::SegmentOutOfFile));
+
+    let kernel = build_elf(super::memory::kernel_virtual_range().start, &code, 0);
+    assert_eq!(reject(&kernel), Some(ElfError::SegmentOutOfUserSpace));
+
+    let mut bad = good.clone();
+    bad[24..32].copy_from_slice(&(base + 0x10000).to_le_bytes());
+    assert_eq!(reject(&bad), Some(ElfError::EntryNotExecutable));
+}
+
+#[test_case]
+fn elf_program_runs_with_args() {
+    let base = user_virtual_range().start + TEST_IMAGE_BASE;
+    let bss = base + 0x2000;
+    let mut code = [
+        0x48, 0x8b, 0x3c, 0x24, // mov rdi, [rsp] (argc)
+        0x48, 0xa1, 0, 0, 0, 0, 0, 0, 0, 0, // mov rax, [bss]
+        0x48, 0x01, 0xc7, // add rdi, rax
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+    ];
+    code[6..14].copy_from_slice(&bss.to_le_bytes());
+    let image = build_elf(base, &code, 0x3000);
+
+    let handle = spawn_program("elf-test", &image, &["elf-test", "a", "b"], &["X=1"])
+        .expect("failed to load test image");
+    assert_eq!(handle.join(), 3);
+}
+
+#[test_case]
+fn elf_segments_may_share_a_page() {
+    use elf::{HEADER_SIZE, PF_R};
+
+    let base = user_virtual_range().start + TEST_IMAGE_BASE;
+    let code_offset = (HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE) as u64;
+    // writable data right after the code, in the same page
+    let data_offset = code_offset + 32;
+    let data = base + data_offset;
+    let mut code = [
+        0x48, 0xa1, 0, 0, 0, 0, 0, 0, 0, 0, // mov rax, [data]
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xa2, 0, 0, 0, 0, 0, 0, 0, 0, // mov [data], al
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+        0x90, 0x90, 0x90, // padding up to the data
+    ];
+    code[2..10].copy_from_slice(&data.to_le_bytes());
+    code[14..22].copy_from_slice(&data.to_le_bytes());
+    let mut body = code.to_vec();
+    body.extend_from_slice(&5u64.to_le_bytes());
+    let text_size = code_offset + code.len() as u64;
+    let text = (PF_R | PF_X, 0, base, text_size, text_size);
+    let data_segment = (PF_R | PF_W, data_offset, data, 8, 8);
+    let image = build_elf_with(base + code_offset, &[text, data_segment], &body);
+
+    let handle = spawn_program("elf-shared-page", &image, &["elf-shared-page"], &[])
+        .expect("segments sharing a page were refused");
+    assert_eq!(handle.join(), 5);
+
+    // sharing bytes is still an error
+    let clash = (PF_R | PF_W, data_offset - 8, data - 8, 16, 16);
+    let image = build_elf_with(base + code_offset, &[text, clash], &body);
+    assert_eq!(
+        Elf::parse(&image, user_virtual_range()).err(),
+        Some(ElfError::OverlappingSegments)
+    );
+}
+
+#[test_case]
+fn shared_page_keeps_text_read_only() {
+    use elf::{HEADER_SIZE, PF_R};
+
+    let base = user_virtual_range().start + TEST_IMAGE_BASE;
+    let code_offset = (HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE) as u64;
+    // text fills a page and a bit, .data starts in its last page
+    let data_offset = 0x1100;
+    let body = alloc::vec![0x90; (data_offset + 8 - code_offset) as usize];
+    let text = (PF_R | PF_X, 0, base, data_offset, data_offset);
+    let data = (PF_R | PF_W, data_offset, base + data_offset, 8, 8);
+    let image = build_elf_with(base + code_offset, &[text, data], &body);
+
+    let space = AddressSpace::new();
+    load(&space, &image, &["wx"], &[]).expect("failed to load test image");
+    let text_page = VirtAddr::new(base);
+    let shared_page = VirtAddr::new(base + 0x1000);
+    assert_eq!(
+        space.handle_fault(text_page, true, false),
+        Err(super::memory::PageFaultError::ProtectionViolation)
+    );
+    assert_eq!(space.handle_fault(text_page, false, true), Ok(()));
+    assert_eq!(space.handle_fault(shared_page, true, false), Ok(()));
+    assert_eq!(space.handle_fault(shared_page, false, true), Ok(()));
+}
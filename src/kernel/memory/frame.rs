@@ -2,7 +2,8 @@ use super::{ADDR_SPACE_MANAGER, PHYS_ADDR_TRANSLATOR};
 use crate::util::bit_set::BitSet;
 use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegionType};
 use core::intrinsics::size_of;
-use heapless::consts::U16;
+use core::ops::Range;
+use heapless::consts::{U16, U64};
 use heapless::Vec;
 use x86_64::{
     structures::paging::{
@@ -14,6 +15,7 @@ use x86_64::{
 const MAX_FRAME_COUNT_USIZE: usize = 1 << 26; // 64M frames
 const MAX_FRAME_COUNT: u64 = MAX_FRAME_COUNT_USIZE as u64;
 const FRAME_SIZE: u64 = 1 << 12; // 4K per frame
+const LOW_MEMORY_END: u64 = 0x100000 / FRAME_SIZE; // first frame above 1M
 
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub struct FrameNumber(u64);
@@ -54,35 +56,77 @@ impl FrameNumber {
 
 type FrameRangeVec = Vec<FrameRange, U16>;
 
+pub const MAX_ORDER: u8 = 10; // up to 1024 contiguous frames (4M) per block
+pub const ORDER_COUNT: usize = MAX_ORDER as usize + 1;
+
 struct BuddyStorage {
     frames: u64,
-    // order0_free: [u8; (MAX_FRAME_COUNT_USIZE + 7) / 8],
+    // one bitmap per order, laid out back to back
     free: [u8],
 }
 
 impl BuddyStorage {
+    fn bitmap_bits(frames: u64, order: u8) -> u64 {
+        frames >> order
+    }
+
+    fn bitmap_bytes(frames: u64, order: u8) -> u64 {
+        num::integer::div_ceil(Self::bitmap_bits(frames, order), 8)
+    }
+
+    fn bitmaps_size(frames: u64) -> u64 {
+        (0..=MAX_ORDER)
+            .map(|order| Self::bitmap_bytes(frames, order))
+            .sum()
+    }
+
     fn size(frames: u64) -> u64 {
-        size_of::<u64>() as u64 + frames / 8
+        size_of::<u64>() as u64 + Self::bitmaps_size(frames)
     }
 
     fn free_bitmap(&mut self, order: u8) -> BitSet<'_> {
-        assert!(order == 0, "not implemented");
-        BitSet::new(self.frames, &mut self.free)
+        assert!(order <= MAX_ORDER, "order too large");
+        let offset: u64 = (0..order)
+            .map(|o| Self::bitmap_bytes(self.frames, o))
+            .sum();
+        let bytes = Self::bitmap_bytes(self.frames, order);
+        BitSet::new(
+            Self::bitmap_bits(self.frames, order),
+            &mut self.free[offset as usize..(offset + bytes) as usize],
+        )
     }
 }
 
+// stored in the first frame of every free block
+#[derive(Copy, Clone)]
+struct FreeNode {
+    prev: FrameNumber,
+    next: FrameNumber,
+}
+
 struct Buddy {
-    free_head: FrameNumber,
+    free_heads: [FrameNumber; ORDER_COUNT],
+    free_blocks: [u64; ORDER_COUNT],
     storage: &'static mut BuddyStorage,
+    // owners beyond the first, per frame, for frames mapped in several places
+    shared: &'static mut [u16],
 }
 
 impl Buddy {
     fn new(mgr: &mut FrameManager, page_table: &mut OffsetPageTable) -> Buddy {
         log::trace!("setting up buddy");
-        let frames = mgr.usable_range.last().unwrap().end_frame_number;
+        let frames = mgr
+            .usable_range
+            .iter()
+            .map(|r| r.end_frame_number)
+            .max()
+            .expect("no usable memory");
         let storage_size = BuddyStorage::size(frames);
+        // the share counts go right after the bitmaps
+        let shared_offset = num::integer::div_ceil(storage_size, 8) * 8;
+        let shared_size = frames * size_of::<u16>() as u64;
 
-        let pages = num::integer::div_ceil(storage_size, FRAME_SIZE);
+        let pages = num::integer::div_ceil(shared_offset + shared_size, FRAME_SIZE);
         let page_range = ADDR_SPACE_MANAGER.get().lock().kernel_alloc(pages);
         log::trace!("buddy storage took {} pages", pages);
 
@@ -103,97 +147,191 @@ impl Buddy {
 
         let storage = unsafe {
             let storage_ptr: *mut () = page_range.start.start_address().as_mut_ptr();
-            let fat_ptr = core::slice::from_raw_parts_mut(storage_ptr, storage_size as usize)
-                as *mut [()] as *mut BuddyStorage;
+            let fat_ptr = core::slice::from_raw_parts_mut(
+                storage_ptr,
+                BuddyStorage::bitmaps_size(frames) as usize,
+            ) as *mut [()] as *mut BuddyStorage;
             &mut *fat_ptr
         };
         storage.frames = frames;
-        storage.free_bitmap(0).set_all(false);
-
-        // set usable range as free
-        for range in mgr.usable_range.iter() {
-            storage
-                .free_bitmap(0)
-                .set_range(range.start_frame_number..range.end_frame_number, true);
+        for order in 0..=MAX_ORDER {
+            storage.free_bitmap(order).set_all(false);
+        }
+        let shared = unsafe {
+            let shared_ptr: *mut u16 =
+                (page_range.start.start_address() + shared_offset).as_mut_ptr();
+            core::slice::from_raw_parts_mut(shared_ptr, frames as usize)
+        };
+        for count in shared.iter_mut() {
+            *count = 0;
         }
 
         let mut buddy = Buddy {
-            free_head: FrameNumber::none(),
+            free_heads: [FrameNumber::none(); ORDER_COUNT],
+            free_blocks: [0; ORDER_COUNT],
             storage,
+            shared,
         };
 
-        buddy.setup_free_links(&mgr.usable_range);
+        // hand every usable frame to the buddy, coalescing as we go
+        for range in mgr.usable_range.iter() {
+            buddy.free_range(range.start_frame_number..range.end_frame_number);
+        }
         log::trace!("setting up buddy - done");
         buddy
     }
 
-    fn setup_free_links(&mut self, usable_range: &FrameRangeVec) {
-        assert!(!usable_range.is_empty(), "wtf?");
-        self.free_head = FrameNumber::from_u64(usable_range.first().unwrap().start_frame_number);
+    fn free_range(&mut self, range: Range<u64>) {
+        // frame 0 doubles as the list terminator
+        let mut idx = core::cmp::max(range.start, 1);
+        while idx < range.end {
+            let mut order = MAX_ORDER;
+            while order > 0 && (idx & ((1 << order) - 1) != 0 || idx + (1 << order) > range.end) {
+                order -= 1;
+            }
+            self.dealloc(order, FrameNumber::from_u64(idx));
+            idx += 1 << order;
+        }
+    }
+
+    fn node(idx: FrameNumber) -> &'static mut FreeNode {
+        let ptr: *mut FreeNode = PHYS_ADDR_TRANSLATOR
+            .get()
+            .translate(idx.into_addr())
+            .as_mut_ptr();
+        unsafe { &mut *ptr }
+    }
 
-        let mut idx_next = FrameNumber::none().into_u64();
+    fn push_free(&mut self, order: u8, idx: FrameNumber) {
+        let mut bitmap = self.storage.free_bitmap(order);
+        let bit = idx.into_u64() >> order;
+        assert!(!bitmap.get(bit), "frame block {:?} freed twice", idx);
+        bitmap.set(bit, true);
 
-        // chain all free frames
-        for idx in usable_range
-            .iter()
-            .flat_map(|range| range.start_frame_number..range.end_frame_number)
-            .rev()
-        {
-            let phys_addr = FrameNumber::from_u64(idx).into_addr();
-            let ptr_idx_ptr: *mut u64 =
-                PHYS_ADDR_TRANSLATOR.get().translate(phys_addr).as_mut_ptr();
-            unsafe {
-                *ptr_idx_ptr = idx_next;
-            }
-            idx_next = idx;
+        let head = self.free_heads[order as usize];
+        *Self::node(idx) = FreeNode {
+            prev: FrameNumber::none(),
+            next: head,
+        };
+        if !head.is_none() {
+            Self::node(head).prev = idx;
         }
+        self.free_heads[order as usize] = idx;
+        self.free_blocks[order as usize] += 1;
     }
 
-    fn alloc(&mut self, order: u8) -> Option<FrameNumber> {
-        assert!(order == 0, "not implemented yet");
-        if self.free_head.is_none() {
-            None
+    fn remove_free(&mut self, order: u8, idx: FrameNumber) {
+        let mut bitmap = self.storage.free_bitmap(order);
+        let bit = idx.into_u64() >> order;
+        assert!(bitmap.get(bit));
+        bitmap.set(bit, false);
+
+        let FreeNode { prev, next } = *Self::node(idx);
+        if prev.is_none() {
+            self.free_heads[order as usize] = next;
         } else {
-            let idx = self.free_head.into_u64();
-            let mut order0_free = self.storage.free_bitmap(0);
-            assert!(order0_free.get(idx));
-            order0_free.set(idx, false);
-
-            let frame = self.free_head.into_frame();
-            let next_idx_ptr: *mut u64 = PHYS_ADDR_TRANSLATOR
-                .get()
-                .translate(frame.start_address())
-                .as_mut_ptr();
-            unsafe {
-                self.free_head = FrameNumber::from_u64(*next_idx_ptr);
-                Some(FrameNumber::from_frame(frame))
-            }
+            Self::node(prev).next = next;
         }
+        if !next.is_none() {
+            Self::node(next).prev = prev;
+        }
+        self.free_blocks[order as usize] -= 1;
     }
 
-    fn dealloc(&mut self, order: u8, start: FrameNumber) {
-        assert!(order == 0, "not implemented yet");
+    fn is_free(&mut self, order: u8, idx: u64) -> bool {
+        let bitmap = self.storage.free_bitmap(order);
+        let bit = idx >> order;
+        bit < bitmap.len() && bitmap.get(bit)
+    }
 
-        let idx = start.into_u64();
-        let mut order0_free = self.storage.free_bitmap(0);
-        assert!(!order0_free.get(idx));
-        order0_free.set(idx, true);
+    fn alloc(&mut self, order: u8) -> Option<FrameNumber> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let found = (order..=MAX_ORDER).find(|&o| !self.free_heads[o as usize].is_none())?;
+        let block = self.free_heads[found as usize];
+        self.remove_free(found, block);
 
-        let next_idx_ptr: *mut u64 = PHYS_ADDR_TRANSLATOR
-            .get()
-            .translate(start.into_addr())
-            .as_mut_ptr();
-        unsafe {
-            *next_idx_ptr = self.free_head.into_u64();
+        // split, giving the upper halves back
+        for o in (order..found).rev() {
+            self.push_free(o, FrameNumber::from_u64(block.into_u64() + (1 << o)));
         }
-        self.free_head = start;
-        unimplemented!()
+        Some(block)
+    }
+
+    fn dealloc(&mut self, order: u8, start: FrameNumber) {
+        assert!(order <= MAX_ORDER, "order too large");
+        let mut idx = start.into_u64();
+        assert!(idx & ((1 << order) - 1) == 0, "misaligned frame block");
+
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = idx ^ (1 << order);
+            if !self.is_free(order, buddy) {
+                break;
+            }
+            self.remove_free(order, FrameNumber::from_u64(buddy));
+            idx &= !(1 << order);
+            order += 1;
+        }
+        self.push_free(order, FrameNumber::from_u64(idx));
+    }
+}
+
+/// Accounting for one region of the bootloader memory map.
+#[derive(Copy, Clone, Debug)]
+pub struct RegionStats {
+    pub region_type: MemoryRegionType,
+    pub range: FrameRange,
+    /// Frames currently held by the buddy. Always 0 for non-usable regions.
+    pub free: u64,
+}
+
+impl RegionStats {
+    pub fn frames(&self) -> u64 {
+        self.range.count()
+    }
+
+    pub fn used(&self) -> u64 {
+        self.frames() - self.free
+    }
+}
+
+/// Snapshot of the frame manager counters.
+#[derive(Copy, Clone, Debug)]
+pub struct FrameStats {
+    /// Usable frames reported by the bootloader.
+    pub total: u64,
+    pub free: u64,
+    /// Frames of every non-usable region (kernel, bootloader, firmware...).
+    pub reserved: u64,
+    /// Peak of `used()` since boot.
+    pub high_water: u64,
+    /// Free blocks sitting in each buddy list.
+    pub free_blocks: [u64; ORDER_COUNT],
+    /// Blocks of each order handed out and not yet returned.
+    pub used_blocks: [u64; ORDER_COUNT],
+    /// Allocations that could not be satisfied, by requested order.
+    pub alloc_failures: [u64; ORDER_COUNT],
+}
+
+impl FrameStats {
+    pub fn used(&self) -> u64 {
+        self.total - self.free
     }
 }
 
+type RegionStatsVec = Vec<RegionStats, U64>;
+
 pub struct FrameManager {
     usable_range: FrameRangeVec,
     buddy: Option<Buddy>,
+    regions: RegionStatsVec,
+    free: u64,
+    high_water: u64,
+    used_blocks: [u64; ORDER_COUNT],
+    alloc_failures: [u64; ORDER_COUNT],
+    low_frame: Option<PhysFrame>,
 }
 
 trait FrameRangeExt {
@@ -211,8 +349,24 @@ impl FrameManager {
         let mut mgr = FrameManager {
             buddy: None,
             usable_range: Default::default(),
+            regions: Default::default(),
+            free: 0,
+            high_water: 0,
+            used_blocks: [0; ORDER_COUNT],
+            alloc_failures: [0; ORDER_COUNT],
+            low_frame: None,
         };
 
+        for region in memory_map.iter() {
+            mgr.regions
+                .push(RegionStats {
+                    region_type: region.region_type,
+                    range: region.range,
+                    free: 0,
+                })
+                .expect("too much memory region!");
+        }
+
         for range in memory_map
             .iter()
             .filter(|r| r.region_type == MemoryRegionType::Usable)
@@ -223,10 +377,56 @@ impl FrameManager {
                 .expect("too much usable region!");
         }
 
+        // real mode code (the startup trampoline of other cpus) can only run
+        // below 1M, keep one frame there out of the buddy
+        if let Some(range) = mgr.usable_range.iter_mut().find(|r| {
+            core::cmp::max(r.start_frame_number, 1)
+                < core::cmp::min(r.end_frame_number, LOW_MEMORY_END)
+        }) {
+            let frame = core::cmp::max(range.start_frame_number, 1);
+            range.start_frame_number = frame + 1;
+            mgr.low_frame = Some(FrameNumber::from_u64(frame).into_frame());
+        }
+
         mgr.buddy = Some(Buddy::new(&mut mgr, page_table));
+
+        // whatever is left of the usable ranges now belongs to the buddy
+        let handed_out: FrameRangeVec = mgr.usable_range.clone();
+        for range in handed_out.iter() {
+            mgr.account(
+                core::cmp::max(range.start_frame_number, 1)..range.end_frame_number,
+                true,
+            );
+        }
+        mgr.high_water = mgr.stats().used();
         mgr
     }
 
+    fn account(&mut self, block: Range<u64>, freed: bool) {
+        for region in self
+            .regions
+            .iter_mut()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+        {
+            let start = core::cmp::max(block.start, region.range.start_frame_number);
+            let end = core::cmp::min(block.end, region.range.end_frame_number);
+            if start < end {
+                if freed {
+                    region.free += end - start;
+                } else {
+                    region.free -= end - start;
+                }
+            }
+        }
+
+        let frames = block.end - block.start;
+        if freed {
+            self.free += frames;
+        } else {
+            self.free -= frames;
+        }
+    }
+
     pub fn alloc(&mut self, order: u8) -> Option<FrameNumber> {
         let result = if let Some(buddy) = &mut self.buddy {
             buddy.alloc(order)
@@ -249,20 +449,100 @@ impl FrameManager {
 
         if let Some(num) = result {
             log::trace!("alloc frame {:?}", num.into_frame());
+            if self.buddy.is_some() {
+                let start = num.into_u64();
+                self.account(start..start + (1 << order), false);
+                self.used_blocks[order as usize] += 1;
+                self.high_water = core::cmp::max(self.high_water, self.stats().used());
+            }
         } else {
             log::trace!("alloc frame FAILED");
+            self.alloc_failures[core::cmp::min(order, MAX_ORDER) as usize] += 1;
         }
         result
     }
 
-    pub fn dealloc(&mut self, _order: u8, start_frame: FrameNumber) {
+    pub fn dealloc(&mut self, order: u8, start_frame: FrameNumber) {
         log::trace!("dealloc frame {:?}", start_frame.into_frame());
         if let Some(buddy) = &mut self.buddy {
-            buddy.dealloc(0, start_frame);
+            assert_eq!(
+                buddy.shared[start_frame.into_u64() as usize],
+                0,
+                "freeing a shared frame"
+            );
+            buddy.dealloc(order, start_frame);
+            let start = start_frame.into_u64();
+            self.account(start..start + (1 << order), true);
+            self.used_blocks[order as usize] -= 1;
         } else {
             panic!("dealloc before buddy setup is meaningless")
         }
     }
+
+    fn shared_count(&mut self, frame: FrameNumber) -> &mut u16 {
+        let buddy = self
+            .buddy
+            .as_mut()
+            .expect("sharing frames before buddy setup");
+        &mut buddy.shared[frame.into_u64() as usize]
+    }
+
+    /// The frame below 1M set aside at boot, if there was one.
+    pub fn low_frame(&self) -> Option<PhysFrame> {
+        self.low_frame
+    }
+
+    /// Adds an owner to an allocated frame, for frames mapped in several
+    /// places at once.
+    pub fn share(&mut self, frame: FrameNumber) {
+        let count = self.shared_count(frame);
+        *count = count.checked_add(1).expect("frame shared too often");
+    }
+
+    /// Number of owners of an allocated frame.
+    pub fn ref_count(&mut self, frame: FrameNumber) -> u32 {
+        *self.shared_count(frame) as u32 + 1
+    }
+
+    /// Drops one owner of a single frame, freeing it along with the last
+    /// one. Returns whether the frame was freed.
+    pub fn release(&mut self, frame: FrameNumber) -> bool {
+        let count = self.shared_count(frame);
+        if *count > 0 {
+            *count -= 1;
+            false
+        } else {
+            self.dealloc(0, frame);
+            true
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let mut stats = FrameStats {
+            total: 0,
+            free: self.free,
+            reserved: 0,
+            high_water: self.high_water,
+            free_blocks: [0; ORDER_COUNT],
+            used_blocks: self.used_blocks,
+            alloc_failures: self.alloc_failures,
+        };
+        for region in self.regions.iter() {
+            if region.region_type == MemoryRegionType::Usable {
+                stats.total += region.frames();
+            } else {
+                stats.reserved += region.frames();
+            }
+        }
+        if let Some(buddy) = &self.buddy {
+            stats.free_blocks = buddy.free_blocks;
+        }
+        stats
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &RegionStats> {
+        self.regions.iter()
+    }
 }
 
 pub struct PagingFrameAllocator<'u> {
@@ -280,3 +560,84 @@ unsafe impl FrameAllocator<Size4KiB> for PagingFrameAllocator<'_> {
         self.manager.alloc(0).map(|n| n.into_frame())
     }
 }
+
+#[test_case]
+fn buddy_alloc_aligned() {
+    let mut mgr = super::FRAME_MANAGER.lock();
+    for order in 0..=MAX_ORDER {
+        let block = mgr.alloc(order).expect("out of frames");
+        assert_eq!(block.into_u64() & ((1 << order) - 1), 0);
+        mgr.dealloc(order, block);
+    }
+}
+
+#[test_case]
+fn buddy_no_overlap() {
+    let mut mgr = super::FRAME_MANAGER.lock();
+    let mut blocks: Vec<(u8, FrameNumber), U16> = Vec::new();
+    for &order in [3, 0, 5, 1, 0, 4, 2, 0].iter() {
+        let block = mgr.alloc(order).expect("out of frames");
+        blocks.push((order, block)).unwrap();
+    }
+    for (i, &(order_a, a)) in blocks.iter().enumerate() {
+        for &(order_b, b) in blocks.iter().skip(i + 1) {
+            let range_a = a.into_u64()..a.into_u64() + (1 << order_a);
+            let range_b = b.into_u64()..b.into_u64() + (1 << order_b);
+            assert!(range_a.end <= range_b.start || range_b.end <= range_a.start);
+        }
+    }
+    for &(order, block) in blocks.iter() {
+        mgr.dealloc(order, block);
+    }
+}
+
+#[test_case]
+fn buddy_coalesce() {
+    let mut mgr = super::FRAME_MANAGER.lock();
+    let block = mgr.alloc(4).expect("out of frames");
+    mgr.dealloc(4, block);
+    // freeing the pieces of a split block must merge them back together
+    let first = mgr.alloc(3).expect("out of frames");
+    let second = mgr.alloc(3).expect("out of frames");
+    mgr.dealloc(3, first);
+    mgr.dealloc(3, second);
+    let merged = mgr.alloc(4).expect("out of frames");
+    assert_eq!(merged.into_u64() & 0xf, 0);
+    mgr.dealloc(4, merged);
+}
+
+#[test_case]
+fn frame_stats_accounting() {
+    let mut mgr = super::FRAME_MANAGER.lock();
+    let before = mgr.stats();
+    assert_eq!(
+        before.free,
+        mgr.regions().map(|r| r.free).sum::<u64>()
+    );
+
+    let block = mgr.alloc(2).expect("out of frames");
+    let during = mgr.stats();
+    assert_eq!(during.free, before.free - 4);
+    assert_eq!(during.used_blocks[2], before.used_blocks[2] + 1);
+    assert!(during.high_water >= during.used());
+
+    mgr.dealloc(2, block);
+    let after = mgr.stats();
+    assert_eq!(after.free, before.free);
+    assert_eq!(after.used_blocks[2], before.used_blocks[2]);
+}
+
+#[test_case]
+fn frame_sharing() {
+    let mut mgr = super::FRAME_MANAGER.lock();
+    let free = mgr.stats().free;
+    let frame = mgr.alloc(0).expect("out of frames");
+    mgr.share(frame);
+    mgr.share(frame);
+    assert_eq!(mgr.ref_count(frame), 3);
+    assert!(!mgr.release(frame));
+    assert!(!mgr.release(frame));
+    assert_eq!(mgr.ref_count(frame), 1);
+    assert!(mgr.release(frame));
+    assert_eq!(mgr.stats().free, free);
+}
@@ -2,10 +2,10 @@ use bootloader::bootinfo::MemoryMap;
 use x86_64::{
     registers::control::*,
     structures::{
-        idt::{InterruptStackFrame, PageFaultErrorCode},
+        idt::PageFaultErrorCode,
         paging::*,
     },
-    VirtAddr,
+    PhysAddr, VirtAddr,
 };
 
 use addr_space::*;
@@ -19,12 +19,21 @@ mod addr_space;
 mod allocator;
 mod frame;
 mod phys_addr_trans;
+mod space;
+mod stack;
+mod vma;
+
+pub use addr_space::{kernel_virtual_range, user_virtual_range};
+pub use space::{kernel_page_table, switch_page_table, AddressSpace, MapError};
+pub use stack::{KernelStack, KERNEL_STACK_PAGES};
+pub use vma::{Backing, FileExtent};
 
 static PHYS_ADDR_TRANSLATOR: InitCell<PhysAddrTranslator> = InitCell::new();
 static ADDR_SPACE_MANAGER: InitCell<MutexInt<AddrSpaceManager>> = InitCell::new();
 static OFFSET_PAGE_TABLE: InitCell<MutexInt<OffsetPageTable>> = InitCell::new();
 // static MAGIC1: u64 = 0xdeadbeef;
 static FRAME_MANAGER: InitCell<MutexInt<FrameManager>> = InitCell::new();
+static KERNEL_L4_FRAME: InitCell<PhysFrame> = InitCell::new();
 
 // static MAGIC2: u64 = 0xdeadbeef;
 
@@ -66,17 +75,224 @@ pub fn init(physical_memory_offset: u64, memory_map: &'static MemoryMap) {
         FrameManager::new(memory_map, OFFSET_PAGE_TABLE.lock().deref_mut()),
     ));
 
+    KERNEL_L4_FRAME.init(Cr3::read().0);
+    populate_kernel_l4();
+
     allocator::init();
+    log_frame_stats();
+}
+
+/// Gives every kernel-half PML4 slot a level 3 table, so address spaces that
+/// copy the boot PML4 keep seeing kernel mappings made after they were
+/// created.
+fn populate_kernel_l4() {
+    let mut page_table = OFFSET_PAGE_TABLE.lock();
+    let mut frame_manager = FRAME_MANAGER.lock();
+    let kernel = kernel_virtual_range();
+    let l4 = page_table.level_4_table();
+    for i in (kernel.start >> 39) as usize..(kernel.end >> 39) as usize {
+        if l4[i].is_unused() {
+            let frame = frame_manager
+                .alloc(0)
+                .expect("out of physical memory")
+                .into_frame();
+            zero_frame(frame);
+            l4[i].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+}
+
+pub use frame::{FrameStats, RegionStats};
+
+pub fn frame_stats() -> FrameStats {
+    FRAME_MANAGER.lock().stats()
+}
+
+pub fn frame_region_stats() -> heapless::Vec<RegionStats, heapless::consts::U64> {
+    FRAME_MANAGER.lock().regions().cloned().collect()
+}
+
+pub fn log_frame_stats() {
+    let frame_manager = FRAME_MANAGER.lock();
+    let stats = frame_manager.stats();
+    log::info!(
+        "frames: total={} free={} used={} reserved={} high_water={}",
+        stats.total,
+        stats.free,
+        stats.used(),
+        stats.reserved,
+        stats.high_water
+    );
+    for order in 0..frame::ORDER_COUNT {
+        if stats.free_blocks[order] != 0
+            || stats.used_blocks[order] != 0
+            || stats.alloc_failures[order] != 0
+        {
+            log::info!(
+                "  order {}: free={} used={} failures={}",
+                order,
+                stats.free_blocks[order],
+                stats.used_blocks[order],
+                stats.alloc_failures[order]
+            );
+        }
+    }
+    for region in frame_manager.regions() {
+        log::info!(
+            "  {:?} {:#x}..{:#x}: frames={} used={}",
+            region.region_type,
+            region.range.start_addr(),
+            region.range.end_addr(),
+            region.frames(),
+            region.used()
+        );
+    }
+}
+
+/// A frame below 1M, for code that has to start in real mode.
+pub fn low_frame() -> Option<PhysFrame> {
+    FRAME_MANAGER.lock().low_frame()
+}
+
+fn identity_page(frame: PhysFrame) -> Page {
+    Page::containing_address(VirtAddr::new(frame.start_address().as_u64()))
+}
+
+/// Maps `frame` at the virtual address equal to its physical one in the
+/// kernel page table, for code that turns paging on while running from it.
+/// Returns false if it already was, in which case it must stay mapped.
+pub fn identity_map(frame: PhysFrame) -> bool {
+    let page = identity_page(frame);
+    let mut page_table = OFFSET_PAGE_TABLE.lock();
+    let mut frame_manager = FRAME_MANAGER.lock();
+    let mut frame_allocator = PagingFrameAllocator::new(&mut *frame_manager);
+    let result = unsafe {
+        page_table.map_to(
+            page,
+            frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            &mut frame_allocator,
+        )
+    };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(mapper::MapToError::PageAlreadyMapped(mapped)) if mapped == frame => false,
+        Err(err) => panic!("failed to identity map {:?}: {:?}", frame, err),
+    }
+}
+
+/// Undoes `identity_map`.
+pub fn identity_unmap(frame: PhysFrame) {
+    let page = identity_page(frame);
+    let (_, flush) = OFFSET_PAGE_TABLE
+        .lock()
+        .unmap(page)
+        .expect("frame was not identity mapped");
+    flush.flush();
+}
+
+/// Where physical memory at `addr` can be read through the bootloader's
+/// mapping of all physical memory.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    PHYS_ADDR_TRANSLATOR.translate(addr)
+}
+
+/// Maps `size` bytes of device memory at `phys` into the kernel range,
+/// uncached, and returns where `phys` ended up.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> VirtAddr {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (size - 1));
+    let frames = PhysFrame::range_inclusive(first, last);
+    let pages = ADDR_SPACE_MANAGER
+        .lock()
+        .kernel_alloc(((last.start_address() - first.start_address()) >> 12) + 1);
+
+    let mut page_table = OFFSET_PAGE_TABLE.lock();
+    let mut frame_manager = FRAME_MANAGER.lock();
+    let mut frame_allocator = PagingFrameAllocator::new(&mut *frame_manager);
+    for (page, frame) in Page::range(pages.start, pages.end).zip(frames) {
+        unsafe {
+            page_table
+                .map_to(
+                    page,
+                    frame,
+                    PageTableFlags::PRESENT
+                        | PageTableFlags::WRITABLE
+                        | PageTableFlags::NO_CACHE
+                        | PageTableFlags::WRITE_THROUGH
+                        | PageTableFlags::NO_EXECUTE,
+                    &mut frame_allocator,
+                )
+                .expect("failed to map device memory")
+                .flush();
+        }
+    }
+    pages.start.start_address() + (phys - first.start_address())
+}
+
+/// `map_to` only marks the leaf entry user accessible, open up the upper
+/// levels on the way to it as well.
+fn set_user_accessible_path(l4: &mut PageTable, page: Page) {
+    let mut table = l4;
+    for &index in [page.p4_index(), page.p3_index(), page.p2_index()].iter() {
+        let entry = &mut table[index];
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+        let next: *mut PageTable = PHYS_ADDR_TRANSLATOR.translate(entry.addr()).as_mut_ptr();
+        table = unsafe { &mut *next };
+    }
+}
+
+fn zero_frame(frame: PhysFrame) {
+    let ptr: *mut u8 = PHYS_ADDR_TRANSLATOR
+        .translate(frame.start_address())
+        .as_mut_ptr();
+    unsafe {
+        core::ptr::write_bytes(ptr, 0, Size4KiB::SIZE as usize);
+    }
+}
+
+/// Leaf entry mapping `page` in `l4`, if all the tables on the way exist.
+fn leaf_entry(
+    l4: &mut PageTable,
+    page: Page,
+) -> Option<&'static mut page_table::PageTableEntry> {
+    let mut table: *mut PageTable = l4;
+    for &index in [page.p4_index(), page.p3_index(), page.p2_index()].iter() {
+        let entry = unsafe { &(*table)[index] };
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = PHYS_ADDR_TRANSLATOR.translate(entry.addr()).as_mut_ptr();
+    }
+    Some(unsafe { &mut (*table)[page.p1_index()] })
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PageFaultError {
+    /// No memory area covers the address.
+    AccessViolation,
+    /// The area does not allow this kind of access.
+    ProtectionViolation,
+    /// A stack ran into its guard page.
+    StackOverflow,
+    OutOfMemory,
 }
 
-pub fn do_page_fault(
-    addr: VirtAddr,
-    _stack_frame: &mut InterruptStackFrame,
-    err: PageFaultErrorCode,
-) {
+pub fn do_page_fault(addr: VirtAddr, err: PageFaultErrorCode) -> Result<(), PageFaultError> {
     crate::call_stack!();
-    if err.contains(PageFaultErrorCode::USER_MODE) {
-        unimplemented!();
+    if user_virtual_range().contains(&addr.as_u64()) {
+        // kernel threads have no user half at all
+        let space = super::sched::current_addr_space().ok_or(PageFaultError::AccessViolation)?;
+        space.handle_fault(
+            addr,
+            err.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+            err.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+        )
+    } else if err.contains(PageFaultErrorCode::USER_MODE) {
+        Err(PageFaultError::AccessViolation)
     } else {
         if !kernel_virtual_range().contains(&addr.as_u64()) {
             panic!("kernel mode page fault, address={:x}", addr.as_u64());
@@ -101,5 +317,6 @@ pub fn do_page_fault(
                 .expect("failed to map virtual memory")
                 .flush();
         }
+        Ok(())
     }
 }
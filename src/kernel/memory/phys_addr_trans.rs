@@ -9,6 +9,10 @@ impl PhysAddrTranslator {
         Self { physical_memory_offset }
     }
 
+    pub fn offset(&self) -> VirtAddr {
+        self.physical_memory_offset
+    }
+
     pub fn translate(&self, addr: PhysAddr) -> VirtAddr {
         self.physical_memory_offset + addr.as_u64()
     }
@@ -0,0 +1,632 @@
+use super::{
+    leaf_entry, set_user_accessible_path, user_virtual_range,
+    vma::{Backing, Vma},
+    zero_frame, FrameManager, FrameNumber, PageFaultError, PagingFrameAllocator, FRAME_MANAGER,
+    KERNEL_L4_FRAME, PHYS_ADDR_TRANSLATOR,
+};
+use crate::util::mutex_int::MutexInt;
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{page::PageRange, page_table::PageTableEntry, *},
+    VirtAddr,
+};
+
+fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    let ptr: *mut PageTable = PHYS_ADDR_TRANSLATOR
+        .translate(frame.start_address())
+        .as_mut_ptr();
+    unsafe { &mut *ptr }
+}
+
+/// PML4 slots covering the user half.
+fn user_l4_indices() -> Range<usize> {
+    let range = user_virtual_range();
+    (range.start >> 39) as usize..(range.end >> 39) as usize
+}
+
+/// Loads `frame` into CR3 unless it is already there.
+pub fn switch_page_table(frame: PhysFrame) {
+    if Cr3::read().0 != frame {
+        unsafe {
+            Cr3::write(frame, Cr3Flags::empty());
+        }
+    }
+}
+
+/// The bootloader's PML4, used by kernel threads.
+pub fn kernel_page_table() -> PhysFrame {
+    *KERNEL_L4_FRAME
+}
+
+/// Marks a page shared with another address space after a fork. It stays
+/// read-only until written, at which point it gets copied.
+const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+struct Inner {
+    page_table: OffsetPageTable<'static>,
+    /// Areas keyed by their end, which stays put when a stack grows down.
+    vmas: BTreeMap<u64, Vma>,
+    // pages handed out by `map_anywhere`, growing down from the top so the
+    // bottom is left to images linked at fixed addresses
+    user_alloc: u64,
+}
+
+impl Inner {
+    /// Area covering `addr`, growing a stack down to it if need be.
+    fn area_for(&mut self, addr: u64) -> Result<&Vma, PageFaultError> {
+        let vma = match self.vmas.range_mut(addr + 1..).next() {
+            Some((_, vma)) => vma,
+            None => return Err(PageFaultError::AccessViolation),
+        };
+        if addr < vma.start {
+            if vma.can_grow_to(addr) {
+                let start = addr & !(Size4KiB::SIZE - 1);
+                log::trace!("growing stack {:#x}..{:#x} to {:#x}", vma.start, vma.end, start);
+                vma.start = start;
+            } else if addr >= vma.reserved_start() {
+                return Err(PageFaultError::StackOverflow);
+            } else {
+                return Err(PageFaultError::AccessViolation);
+            }
+        }
+        Ok(vma)
+    }
+
+    /// Makes sure the page holding `addr` is mapped, and privately so if it
+    /// is about to be written, then returns where the kernel can reach its
+    /// frame. The area's permissions are only enforced if `checked`.
+    fn populate(
+        &mut self,
+        addr: u64,
+        write: bool,
+        exec: bool,
+        checked: bool,
+    ) -> Result<VirtAddr, PageFaultError> {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let (flags, key) = {
+            let vma = self.area_for(addr)?;
+            if checked && !vma.allows(write, exec) {
+                return Err(PageFaultError::ProtectionViolation);
+            }
+            (vma.flags, vma.end)
+        };
+        if let Some(entry) = leaf_entry(self.page_table.level_4_table(), page) {
+            if !entry.is_unused() {
+                if write && entry.flags().contains(COW) {
+                    return break_cow(page, entry);
+                }
+                // raced with another fault on the same page
+                return Ok(PHYS_ADDR_TRANSLATOR.translate(entry.addr()));
+            }
+        }
+
+        let mut frame_manager = FRAME_MANAGER.lock();
+        let mut frame_allocator = PagingFrameAllocator::new(&mut *frame_manager);
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(PageFaultError::OutOfMemory)?;
+        zero_frame(frame);
+        let kernel_view = PHYS_ADDR_TRANSLATOR.translate(frame.start_address());
+        self.vmas[&key].fill(page.start_address().as_u64(), kernel_view.as_mut_ptr());
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        unsafe {
+            // not present before, nothing to flush
+            self.page_table
+                .map_to(page, frame, flags, &mut frame_allocator)
+                .map_err(|_| PageFaultError::OutOfMemory)?
+                .ignore();
+        }
+        set_user_accessible_path(self.page_table.level_4_table(), page);
+        Ok(kernel_view)
+    }
+}
+
+/// Gives `page` a frame of its own on the first write after a fork. The last
+/// owner of a shared frame just takes it over.
+fn break_cow(page: Page, entry: &mut PageTableEntry) -> Result<VirtAddr, PageFaultError> {
+    let mut frame_manager = FRAME_MANAGER.lock();
+    let old = FrameNumber::from_addr(entry.addr());
+    let mut flags = entry.flags();
+    flags.remove(COW);
+    flags.insert(PageTableFlags::WRITABLE);
+    if frame_manager.ref_count(old) > 1 {
+        let new = frame_manager.alloc(0).ok_or(PageFaultError::OutOfMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                PHYS_ADDR_TRANSLATOR.translate(old.into_addr()).as_ptr::<u8>(),
+                PHYS_ADDR_TRANSLATOR.translate(new.into_addr()).as_mut_ptr::<u8>(),
+                Size4KiB::SIZE as usize,
+            );
+        }
+        frame_manager.release(old);
+        entry.set_addr(new.into_addr(), flags);
+    } else {
+        entry.set_flags(flags);
+    }
+    x86_64::instructions::tlb::flush(page.start_address());
+    Ok(PHYS_ADDR_TRANSLATOR.translate(entry.addr()))
+}
+
+/// Calls `f` on every mapped page of the user half of `l4`.
+fn for_each_user_page(l4: &mut PageTable, mut f: impl FnMut(Page, &mut PageTableEntry)) {
+    for i4 in user_l4_indices() {
+        if l4[i4].is_unused() {
+            continue;
+        }
+        let l3 = table_at(PhysFrame::containing_address(l4[i4].addr()));
+        for (i3, l3_entry) in l3.iter_mut().enumerate().filter(|(_, e)| !e.is_unused()) {
+            let l2 = table_at(PhysFrame::containing_address(l3_entry.addr()));
+            for (i2, l2_entry) in l2.iter_mut().enumerate().filter(|(_, e)| !e.is_unused()) {
+                let l1 = table_at(PhysFrame::containing_address(l2_entry.addr()));
+                for (i1, entry) in l1.iter_mut().enumerate().filter(|(_, e)| !e.is_unused()) {
+                    let addr = ((i4 << 39) | (i3 << 30) | (i2 << 21) | (i1 << 12)) as u64;
+                    f(Page::containing_address(VirtAddr::new(addr)), entry);
+                }
+            }
+        }
+    }
+}
+
+/// Why an area could not be added.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MapError {
+    OutOfUserSpace,
+    Overlapping,
+}
+
+/// Page tables and memory areas of a process. The kernel half of the PML4 is
+/// a copy of the boot PML4, whose kernel slots are all populated during
+/// `init`, so kernel mappings made later show up in every address space. The
+/// user half is private, populated on demand from the areas, and torn down
+/// with it.
+pub struct AddressSpace {
+    l4_frame: PhysFrame,
+    inner: MutexInt<Inner>,
+}
+
+impl AddressSpace {
+    pub fn new() -> AddressSpace {
+        let l4_frame = FRAME_MANAGER
+            .lock()
+            .alloc(0)
+            .expect("out of physical memory")
+            .into_frame();
+        let l4 = table_at(l4_frame);
+        let kernel_l4 = table_at(kernel_page_table());
+        let user = user_l4_indices();
+        for (i, entry) in l4.iter_mut().enumerate() {
+            let kernel_entry = &kernel_l4[i];
+            if user.contains(&i) || kernel_entry.is_unused() {
+                entry.set_unused();
+            } else {
+                entry.set_addr(kernel_entry.addr(), kernel_entry.flags());
+            }
+        }
+
+        let page_table = unsafe { OffsetPageTable::new(l4, PHYS_ADDR_TRANSLATOR.offset()) };
+        log::trace!("new address space {:?}", l4_frame);
+        AddressSpace {
+            l4_frame,
+            inner: MutexInt::new(
+                true,
+                Inner {
+                    page_table,
+                    vmas: BTreeMap::new(),
+                    user_alloc: 0,
+                },
+            ),
+        }
+    }
+
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.l4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4_frame
+    }
+
+    pub fn activate(&self) {
+        switch_page_table(self.l4_frame);
+    }
+
+    /// Adds an area covering `range`, which must not overlap any other area
+    /// or stack reservation.
+    pub fn map_fixed(
+        &self,
+        range: PageRange,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<(), MapError> {
+        let vma = Vma {
+            start: range.start.start_address().as_u64(),
+            end: range.end.start_address().as_u64(),
+            flags,
+            backing,
+        };
+        let user = user_virtual_range();
+        if vma.reserved_start() < user.start || vma.end > user.end || vma.start >= vma.end {
+            return Err(MapError::OutOfUserSpace);
+        }
+
+        let mut inner = self.inner.lock();
+        let overlaps = inner
+            .vmas
+            .range(vma.reserved_start() + 1..)
+            .next()
+            .map_or(false, |(_, other)| other.reserved_start() < vma.end);
+        if overlaps {
+            return Err(MapError::Overlapping);
+        }
+        inner.vmas.insert(vma.end, vma);
+        Ok(())
+    }
+
+    /// Adds an area of `pages` pages somewhere in the user half. Stacks get
+    /// room to grow to their limit below that.
+    pub fn map_anywhere(
+        &self,
+        pages: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<PageRange, MapError> {
+        let reserved = match backing {
+            Backing::Stack { limit } => {
+                assert!(pages * Size4KiB::SIZE <= limit, "stack larger than its limit");
+                limit / Size4KiB::SIZE + 1
+            }
+            _ => pages,
+        };
+        let end = {
+            let mut inner = self.inner.lock();
+            let user = user_virtual_range();
+            let available = (user.end - user.start) / Size4KiB::SIZE - inner.user_alloc;
+            if reserved > available {
+                return Err(MapError::OutOfUserSpace);
+            }
+            let end = Page::containing_address(VirtAddr::new(user.end)) - inner.user_alloc;
+            inner.user_alloc += reserved;
+            end
+        };
+        let range = PageRange {
+            start: end - pages,
+            end,
+        };
+        // may run into an area mapped at a fixed address
+        self.map_fixed(range, flags, backing)?;
+        Ok(range)
+    }
+
+    /// Adds an anonymous area of `pages` zeroed pages somewhere in the user
+    /// half. `PRESENT` and `USER_ACCESSIBLE` are implied.
+    pub fn alloc_user_pages(
+        &self,
+        pages: u64,
+        flags: PageTableFlags,
+    ) -> Result<PageRange, MapError> {
+        self.map_anywhere(pages, flags, Backing::Anonymous)
+    }
+
+    /// Resolves a fault on `addr`: maps the page if an area allows the
+    /// access, growing stacks as needed.
+    pub fn handle_fault(
+        &self,
+        addr: VirtAddr,
+        write: bool,
+        exec: bool,
+    ) -> Result<(), PageFaultError> {
+        self.inner
+            .lock()
+            .populate(addr.as_u64(), write, exec, true)
+            .map(|_| ())
+    }
+
+    /// Checks that every page touching `range` may be accessed from ring 3
+    /// (and written, if asked), and maps them so the kernel can touch them
+    /// without faulting.
+    pub fn fault_in_user_range(
+        &self,
+        range: Range<u64>,
+        writable: bool,
+    ) -> Result<(), PageFaultError> {
+        if range.start >= range.end {
+            return Ok(());
+        }
+        let mut inner = self.inner.lock();
+        let mut page = range.start & !(Size4KiB::SIZE - 1);
+        while page < range.end {
+            inner.populate(page, writable, false, true)?;
+            page += Size4KiB::SIZE;
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to `addr` through the kernel's view of the backing
+    /// frames, so it works whether or not this address space is active and
+    /// regardless of page permissions. Fails if a page is outside any area or
+    /// no frame is left for it.
+    pub fn copy_to_user(&self, addr: VirtAddr, data: &[u8]) -> Result<(), PageFaultError> {
+        let mut inner = self.inner.lock();
+        let mut done = 0;
+        while done < data.len() {
+            let addr = addr + done as u64;
+            let page = Page::<Size4KiB>::containing_address(addr);
+            let offset = (addr - page.start_address()) as usize;
+            let len = core::cmp::min(data.len() - done, 4096 - offset);
+            let frame = inner.populate(addr.as_u64(), true, false, false)?;
+            unsafe {
+                let dst = frame.as_mut_ptr::<u8>();
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(), dst.add(offset), len);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes at `addr`, the counterpart of `copy_to_user`.
+    pub fn copy_from_user(&self, addr: VirtAddr, buf: &mut [u8]) {
+        let mut inner = self.inner.lock();
+        let mut done = 0;
+        while done < buf.len() {
+            let addr = addr + done as u64;
+            let page = Page::<Size4KiB>::containing_address(addr);
+            let offset = (addr - page.start_address()) as usize;
+            let len = core::cmp::min(buf.len() - done, 4096 - offset);
+            let frame = inner
+                .populate(addr.as_u64(), false, false, false)
+                .expect("copy from user memory outside any area");
+            unsafe {
+                let src = frame.as_ptr::<u8>();
+                core::ptr::copy_nonoverlapping(src.add(offset), buf[done..].as_mut_ptr(), len);
+            }
+            done += len;
+        }
+    }
+
+    /// Duplicates the address space. Pages mapped so far are shared with
+    /// the copy, writable ones copy-on-write on both sides; the rest is
+    /// populated independently on demand.
+    pub fn fork(&self) -> AddressSpace {
+        let child = AddressSpace::new();
+        {
+            let mut inner = self.inner.lock();
+            let mut child_inner = child.inner.lock();
+            child_inner.vmas = inner.vmas.clone();
+            child_inner.user_alloc = inner.user_alloc;
+
+            let mut frame_manager = FRAME_MANAGER.lock();
+            let child_table = &mut child_inner.page_table;
+            for_each_user_page(inner.page_table.level_4_table(), |page, entry| {
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags.remove(PageTableFlags::WRITABLE);
+                    flags.insert(COW);
+                    entry.set_flags(flags);
+                }
+                frame_manager.share(FrameNumber::from_addr(entry.addr()));
+                let frame = PhysFrame::containing_address(entry.addr());
+                let mut frame_allocator = PagingFrameAllocator::new(&mut *frame_manager);
+                unsafe {
+                    child_table
+                        .map_to(page, frame, flags, &mut frame_allocator)
+                        .expect("out of memory forking an address space")
+                        .ignore();
+                }
+                set_user_accessible_path(child_table.level_4_table(), page);
+            });
+        }
+        // the parent lost write access to everything it shared
+        if self.is_active() {
+            x86_64::instructions::tlb::flush_all();
+        }
+        child
+    }
+}
+
+/// Frees every frame below `table`: the lower tables and, at the bottom,
+/// this address space's share of the pages they map.
+fn free_table(frame_manager: &mut FrameManager, table: &PageTable, level: u8) {
+    for entry in table.iter() {
+        if entry.is_unused() {
+            continue;
+        }
+        assert!(
+            !entry.flags().contains(PageTableFlags::HUGE_PAGE),
+            "huge pages in the user half"
+        );
+        let frame = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            free_table(frame_manager, table_at(frame), level - 1);
+            frame_manager.dealloc(0, FrameNumber::from_frame(frame));
+        } else {
+            // pages may still be shared with forks
+            frame_manager.release(FrameNumber::from_frame(frame));
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        log::trace!("dropping address space {:?}", self.l4_frame);
+        let l4 = table_at(self.l4_frame);
+        let mut frame_manager = FRAME_MANAGER.lock();
+        for i in user_l4_indices() {
+            let entry = &l4[i];
+            if !entry.is_unused() {
+                let frame = PhysFrame::containing_address(entry.addr());
+                free_table(&mut frame_manager, table_at(frame), 3);
+                frame_manager.dealloc(0, FrameNumber::from_frame(frame));
+            }
+        }
+        frame_manager.dealloc(0, FrameNumber::from_frame(self.l4_frame));
+    }
+}
+
+#[cfg(test)]
+use crate::kernel::{sched::EXIT_SEGV, user::run_user_code};
+
+#[test_case]
+fn address_space_is_isolated_and_freed() {
+    use super::frame_stats;
+
+    let before = frame_stats().free;
+    let a = AddressSpace::new();
+    let b = AddressSpace::new();
+    let range = a.alloc_user_pages(2, PageTableFlags::WRITABLE).unwrap();
+    let addr = range.start.start_address().as_u64();
+    assert_eq!(a.fault_in_user_range(addr..addr + 2 * 4096, true), Ok(()));
+    assert_eq!(
+        b.fault_in_user_range(addr..addr + 1, false),
+        Err(PageFaultError::AccessViolation)
+    );
+
+    // kernel half is shared
+    let kernel = table_at(kernel_page_table());
+    for i in (0..512).filter(|i| !user_l4_indices().contains(i)) {
+        assert_eq!(table_at(a.l4_frame())[i].addr(), kernel[i].addr());
+    }
+
+    drop(a);
+    drop(b);
+    assert_eq!(frame_stats().free, before);
+}
+
+#[test_case]
+fn faults_follow_area_permissions() {
+    let space = AddressSpace::new();
+    let ro = space.alloc_user_pages(1, PageTableFlags::NO_EXECUTE).unwrap();
+    let ro = ro.start.start_address();
+    assert_eq!(space.handle_fault(ro, false, false), Ok(()));
+    assert_eq!(
+        space.handle_fault(ro, true, false),
+        Err(PageFaultError::ProtectionViolation)
+    );
+    assert_eq!(
+        space.handle_fault(ro, false, true),
+        Err(PageFaultError::ProtectionViolation)
+    );
+    assert_eq!(
+        space.handle_fault(VirtAddr::new(user_virtual_range().start), false, false),
+        Err(PageFaultError::AccessViolation)
+    );
+}
+
+#[test_case]
+fn stack_grows_down_to_its_limit() {
+    let space = AddressSpace::new();
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let stack = space.map_anywhere(1, flags, Backing::Stack { limit: 4 * 4096 }).unwrap();
+    let top = stack.end.start_address();
+    assert_eq!(space.handle_fault(top - 4 * 4096u64, true, false), Ok(()));
+    assert_eq!(
+        space.handle_fault(top - 5 * 4096u64, true, false),
+        Err(PageFaultError::StackOverflow)
+    );
+}
+
+#[test_case]
+fn map_anywhere_fails_once_user_space_is_used_up() {
+    let space = AddressSpace::new();
+    let user = user_virtual_range();
+    let pages = (user.end - user.start) / 4096;
+    assert_eq!(
+        space.alloc_user_pages(pages + 1, PageTableFlags::empty()),
+        Err(MapError::OutOfUserSpace)
+    );
+    assert!(space.alloc_user_pages(pages / 2, PageTableFlags::empty()).is_ok());
+    assert_eq!(
+        space.alloc_user_pages(pages / 2 + 1, PageTableFlags::empty()),
+        Err(MapError::OutOfUserSpace)
+    );
+}
+
+#[test_case]
+fn user_memory_is_mapped_on_demand() {
+    let code = [
+        0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, MMAP
+        0x31, 0xff, // xor edi, edi
+        0xbe, 0x00, 0x10, 0x00, 0x00, // mov esi, 4096
+        0xba, 0x03, 0x00, 0x00, 0x00, // mov edx, PROT_READ | PROT_WRITE
+        0x0f, 0x05, // syscall
+        0xc6, 0x00, 0x2a, // mov byte ptr [rax], 42
+        0x0f, 0xb6, 0x38, // movzx edi, byte ptr [rax]
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(run_user_code("demand-paging", &code), 42);
+}
+
+#[test_case]
+fn user_write_to_read_only_memory_kills_thread() {
+    let code = [
+        0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, MMAP
+        0x31, 0xff, // xor edi, edi
+        0xbe, 0x00, 0x10, 0x00, 0x00, // mov esi, 4096
+        0xba, 0x01, 0x00, 0x00, 0x00, // mov edx, PROT_READ
+        0x0f, 0x05, // syscall
+        0xc6, 0x00, 0x2a, // mov byte ptr [rax], 42
+        0x31, 0xff, // xor edi, edi
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(run_user_code("read-only", &code), EXIT_SEGV);
+}
+
+#[test_case]
+fn user_stack_grows_until_the_guard() {
+    let mut code = [
+        0x48, 0x81, 0xec, 0, 0, 0, 0, // sub rsp, imm32
+        0x48, 0xc7, 0x04, 0x24, 0x01, 0x00, 0x00, 0x00, // mov qword ptr [rsp], 1
+        0x31, 0xff, // xor edi, edi
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+    ];
+    code[3..7].copy_from_slice(&(8 * 4096u32).to_le_bytes());
+    assert_eq!(run_user_code("stack-grow", &code), 0);
+    code[3..7].copy_from_slice(&(64 * 4096u32).to_le_bytes());
+    assert_eq!(run_user_code("stack-overflow", &code), EXIT_SEGV);
+}
+
+#[test_case]
+fn fork_shares_pages_until_written() {
+    use super::frame_stats;
+
+    let free = frame_stats().free;
+    let parent = AddressSpace::new();
+    let range = parent.alloc_user_pages(2, PageTableFlags::WRITABLE).unwrap();
+    let addr = range.start.start_address();
+    parent.copy_to_user(addr, b"parent").unwrap();
+    parent.copy_to_user(addr + 4096u64, b"shared").unwrap();
+
+    let child = parent.fork();
+    let mut buf = [0u8; 6];
+    child.copy_from_user(addr, &mut buf);
+    assert_eq!(&buf, b"parent");
+
+    child.copy_to_user(addr, b"child!").unwrap();
+    parent.copy_from_user(addr, &mut buf);
+    assert_eq!(&buf, b"parent");
+    child.copy_from_user(addr, &mut buf);
+    assert_eq!(&buf, b"child!");
+
+    parent.copy_to_user(addr, b"parent").unwrap();
+    child.copy_from_user(addr, &mut buf);
+    assert_eq!(&buf, b"child!");
+
+    // the untouched page is still one frame between the two
+    child.copy_from_user(addr + 4096u64, &mut buf);
+    assert_eq!(&buf, b"shared");
+    let shared = {
+        let mut inner = parent.inner.lock();
+        let page = Page::containing_address(addr + 4096u64);
+        leaf_entry(inner.page_table.level_4_table(), page).unwrap().addr()
+    };
+    assert_eq!(FRAME_MANAGER.lock().ref_count(FrameNumber::from_addr(shared)), 2);
+
+    drop(parent);
+    drop(child);
+    assert_eq!(frame_stats().free, free);
+}
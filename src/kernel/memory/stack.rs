@@ -0,0 +1,96 @@
+use super::{FrameNumber, PagingFrameAllocator, ADDR_SPACE_MANAGER, FRAME_MANAGER, OFFSET_PAGE_TABLE};
+use crate::util::mutex_int::MutexInt;
+use alloc::vec::Vec;
+use x86_64::{
+    structures::paging::{page::PageRange, FrameAllocator, Mapper, Page, PageTableFlags},
+    VirtAddr,
+};
+
+pub const KERNEL_STACK_PAGES: u64 = 16; // 64K
+
+// ranges of dropped stacks, guard page included, for new stacks to reuse as
+// kernel address space is never given back
+static FREE_RANGES: MutexInt<Vec<PageRange>> = MutexInt::new(false, Vec::new());
+
+/// An eagerly mapped kernel stack. The page right below it is left unmapped,
+/// so an overflow faults while pushing the exception frame and ends up in the
+/// double fault handler instead of silently corrupting memory.
+pub struct KernelStack {
+    pages: PageRange,
+}
+
+impl KernelStack {
+    pub fn new(pages: u64) -> KernelStack {
+        let range = reuse_range(pages + 1)
+            .unwrap_or_else(|| ADDR_SPACE_MANAGER.lock().kernel_alloc(pages + 1));
+        let pages = Page::range(range.start + 1, range.end);
+
+        let mut page_table = OFFSET_PAGE_TABLE.lock();
+        let mut frame_manager = FRAME_MANAGER.lock();
+        let mut frame_allocator = PagingFrameAllocator::new(&mut *frame_manager);
+        for page in Page::range(pages.start, pages.end) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .expect("out of physical memory");
+            unsafe {
+                page_table
+                    .map_to(
+                        page,
+                        frame,
+                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                        &mut frame_allocator,
+                    )
+                    .expect("failed to map kernel stack")
+                    .flush();
+            }
+        }
+        KernelStack { pages }
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.pages.end.start_address()
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.pages.start.start_address()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut page_table = OFFSET_PAGE_TABLE.lock();
+        let mut frame_manager = FRAME_MANAGER.lock();
+        for page in Page::range(self.pages.start, self.pages.end) {
+            let (frame, flush) = page_table
+                .unmap(page)
+                .expect("kernel stack page not mapped");
+            flush.flush();
+            frame_manager.dealloc(0, FrameNumber::from_frame(frame));
+        }
+        drop(frame_manager);
+        drop(page_table);
+        FREE_RANGES.lock().push(PageRange {
+            start: self.pages.start - 1,
+            end: self.pages.end,
+        });
+    }
+}
+
+fn reuse_range(pages: u64) -> Option<PageRange> {
+    let mut free = FREE_RANGES.lock();
+    let index = free
+        .iter()
+        .position(|range| range.end - range.start == pages)?;
+    Some(free.swap_remove(index))
+}
+
+#[test_case]
+fn dropped_stack_range_is_reused() {
+    let stack = KernelStack::new(KERNEL_STACK_PAGES);
+    let bottom = stack.bottom();
+    drop(stack);
+    let stack = KernelStack::new(KERNEL_STACK_PAGES);
+    assert_eq!(stack.bottom(), bottom);
+    // the stack is mapped again
+    unsafe { stack.bottom().as_mut_ptr::<u64>().write_volatile(1) };
+}
@@ -0,0 +1,85 @@
+use alloc::{sync::Arc, vec::Vec};
+use x86_64::structures::paging::PageTableFlags;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Where the contents of a page come from the first time it is touched.
+#[derive(Clone)]
+pub enum Backing {
+    /// Zero filled.
+    Anonymous,
+    /// Pieces of `file` placed at fixed addresses, one per ELF segment
+    /// sharing the area. Everything else reads as zero (.bss).
+    File {
+        file: Arc<[u8]>,
+        extents: Vec<FileExtent>,
+    },
+    /// Zero filled, and grows down when touched below its start as long as
+    /// it stays within `limit` bytes. The page below the limit is a guard
+    /// that never gets mapped.
+    Stack { limit: u64 },
+}
+
+/// `len` bytes of a file from `offset` on, placed at `addr`.
+#[derive(Copy, Clone, Debug)]
+pub struct FileExtent {
+    pub addr: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// A range of the user half with uniform permissions and backing. Pages are
+/// only mapped when first touched.
+#[derive(Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    /// `WRITABLE` and `NO_EXECUTE` are honoured, `PRESENT` and
+    /// `USER_ACCESSIBLE` are implied.
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    /// Lowest address the area may ever cover, a stack's guard page included.
+    pub fn reserved_start(&self) -> u64 {
+        match self.backing {
+            Backing::Stack { limit } => self.end - limit - PAGE_SIZE,
+            _ => self.start,
+        }
+    }
+
+    /// Whether `addr`, below the start, is a legal place to grow a stack to.
+    pub fn can_grow_to(&self, addr: u64) -> bool {
+        match self.backing {
+            Backing::Stack { limit } => addr < self.start && addr >= self.end - limit,
+            _ => false,
+        }
+    }
+
+    pub fn allows(&self, write: bool, exec: bool) -> bool {
+        (!write || self.flags.contains(PageTableFlags::WRITABLE))
+            && (!exec || !self.flags.contains(PageTableFlags::NO_EXECUTE))
+    }
+
+    /// Fills in the page at `page_addr` through `dst`, which points to an
+    /// already zeroed frame.
+    pub fn fill(&self, page_addr: u64, dst: *mut u8) {
+        if let Backing::File { file, extents } = &self.backing {
+            let page_end = page_addr + PAGE_SIZE;
+            for extent in extents.iter() {
+                let start = core::cmp::max(page_addr, extent.addr);
+                let end = core::cmp::min(page_end, extent.addr + extent.len);
+                if start >= end {
+                    continue;
+                }
+                let from = (extent.offset + (start - extent.addr)) as usize;
+                let count = (end - start) as usize;
+                unsafe {
+                    let dst = dst.add((start - page_addr) as usize);
+                    core::ptr::copy_nonoverlapping(file[from..from + count].as_ptr(), dst, count);
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use crate::kernel::time::get_real_time;
+use crate::kernel::time::Instant;
+use core::time::Duration;
 
 pub fn halt_loop() {
     loop {
@@ -6,30 +7,19 @@ pub fn halt_loop() {
     }
 }
 
-pub fn sleep(time: u64) {
-    let before = get_real_time();
-    loop {
-        x86_64::instructions::hlt();
-        let current = get_real_time();
-        if current - before >= time {
-            break;
-        }
-    }
-}
-
 pub struct BenchmarkHandle {
-    start_time: u64
+    start_time: Instant
 }
 
 impl BenchmarkHandle {
-    pub fn time(&self) -> u64 {
-        return get_real_time() - self.start_time;
+    pub fn time(&self) -> Duration {
+        return self.start_time.elapsed();
     }
     pub fn print(&self) {
-        println!("time={}", self.time());
+        println!("time={:?}", self.time());
     }
 }
 
 pub fn benchmark() -> BenchmarkHandle {
-    BenchmarkHandle { start_time: get_real_time() }
+    BenchmarkHandle { start_time: Instant::now() }
 }
\ No newline at end of file
@@ -1,26 +1,71 @@
+mod acpi;
+mod exception;
 mod int;
+mod irq;
 mod gdt;
+mod irqchip;
+mod keyboard;
+mod loader;
 mod time;
 mod memory;
 mod misc;
+pub mod power;
 mod sched;
+mod smp;
+mod softirq;
+mod sync;
+mod syscall;
+mod user;
+mod workqueue;
 
-// use time::get_real_time;
 use bootloader::BootInfo;
-pub use time::subscribe_timer;
-pub use int::is_interrupt_context;
+pub use time::{sleep, subscribe_timer, wall_clock, DateTime, Instant, Timer};
+pub use sync::{
+    Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, Semaphore, WaitQueue,
+};
+pub use workqueue::{schedule_work, Work};
+pub use smp::{cpu_count, current_id as current_cpu, MAX_CPUS};
+pub use int::{interrupt_depth, is_interrupt_context, InterruptDepth};
+pub use sched::{
+    current as current_thread, current_addr_space, exit as exit_thread, preempt_disable,
+    preempt_enable, set_policy as set_sched_policy, spawn, spawn_in, spawn_with, yield_now,
+    JoinHandle, PolicyKind as SchedPolicy, SchedParam, ThreadId,
+};
+pub use irq::{irq_stats, register_irq, unregister_irq, IrqError, IrqHandler, IrqReturn, IrqStats};
+pub use syscall::{nr as syscall_nr, SyscallError};
+pub use loader::{exec, spawn_program, ElfError, LoadError, LoadedProgram};
+pub use user::enter_user_mode;
+pub use memory::{
+    frame_region_stats, frame_stats, log_frame_stats, AddressSpace, Backing, FrameStats,
+    MapError, RegionStats,
+};
 
 pub fn init(boot_info: &'static BootInfo) {
     crate::call_stack!();
+    // logging looks at cpu-local state
+    smp::init_boot_cpu();
 
     log::trace!("initializing kernel");
     gdt::init();
     int::init();
+    syscall::init();
     time::init();
+    keyboard::init();
     memory::init(boot_info.physical_memory_offset, &boot_info.memory_map);
+    acpi::init();
+    irqchip::init(
+        acpi::madt()
+            .and_then(|madt| madt.apic_config())
+            .unwrap_or_else(irqchip::ApicConfig::legacy),
+    );
+    time::init_late();
+    sched::init();
+    workqueue::init();
+    smp::init();
 }
 
 pub fn start() -> ! {
     log::info!("kernel running");
-    loop {}
+    // the boot thread is done, leave the cpu to the spawned threads and idle
+    sched::exit(0)
 }
\ No newline at end of file
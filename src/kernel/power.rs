@@ -0,0 +1,157 @@
+use super::acpi::{self, GenericAddress, RegisterSpace};
+use super::memory::map_mmio;
+use core::sync::atomic::spin_loop_hint;
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::PhysAddr;
+
+const SCI_EN: u16 = 1;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+/// Gives a reset or power off some time to take effect before trying the
+/// next way.
+fn settle() {
+    for _ in 0..10_000_000 {
+        spin_loop_hint();
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Switches the firmware to ACPI mode, unless it is there already.
+fn enable_acpi(fadt: &acpi::Fadt) {
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control);
+    if unsafe { pm1a.read() } & SCI_EN != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    unsafe {
+        Port::<u8>::new(fadt.smi_command).write(fadt.acpi_enable);
+    }
+    for _ in 0..1_000_000 {
+        if unsafe { pm1a.read() } & SCI_EN != 0 {
+            return;
+        }
+        spin_loop_hint();
+    }
+    log::warn!("firmware did not enter acpi mode");
+}
+
+fn acpi_power_off() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("no fadt")?;
+    let s5 = acpi::s5().ok_or("no _S5 object")?;
+    enable_acpi(fadt);
+    unsafe {
+        let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control);
+        let value = pm1a.read() & !(0b111 << SLP_TYP_SHIFT);
+        pm1a.write(value | ((s5.a as u16) << SLP_TYP_SHIFT) | SLP_EN);
+        if let Some(port) = fadt.pm1b_control {
+            let mut pm1b: Port<u16> = Port::new(port);
+            let value = pm1b.read() & !(0b111 << SLP_TYP_SHIFT);
+            pm1b.write(value | ((s5.b as u16) << SLP_TYP_SHIFT) | SLP_EN);
+        }
+    }
+    settle();
+    Err("still running after entering S5")
+}
+
+fn write_register(reg: GenericAddress, value: u8) -> Result<(), &'static str> {
+    match reg.space {
+        RegisterSpace::SystemIo => unsafe {
+            Port::<u8>::new(reg.address as u16).write(value);
+        },
+        RegisterSpace::SystemMemory => {
+            let addr = map_mmio(PhysAddr::new(reg.address), 1);
+            unsafe { core::ptr::write_volatile(addr.as_mut_ptr::<u8>(), value) };
+        }
+        // device and function in the upper words, offset in the lowest, on
+        // bus 0
+        RegisterSpace::PciConfig => {
+            let device = ((reg.address >> 32) & 0x1f) as u32;
+            let function = ((reg.address >> 16) & 0x7) as u32;
+            let offset = (reg.address & 0xff) as u32;
+            unsafe {
+                Port::<u32>::new(PCI_CONFIG_ADDRESS)
+                    .write(0x8000_0000 | (device << 11) | (function << 8) | (offset & 0xfc));
+                Port::<u8>::new(PCI_CONFIG_DATA + (offset & 3) as u16).write(value);
+            }
+        }
+        RegisterSpace::Other(_) => return Err("reset register in unsupported space"),
+    }
+    Ok(())
+}
+
+fn acpi_reset() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("no fadt")?;
+    let (reg, value) = fadt.reset.ok_or("no reset register")?;
+    write_register(reg, value)?;
+    settle();
+    Err("still running after the reset register write")
+}
+
+/// Pulses the cpu reset line through the 8042.
+fn keyboard_controller_reset() -> Result<(), &'static str> {
+    if !acpi::fadt().map_or(true, |fadt| fadt.has_8042) {
+        return Err("no keyboard controller");
+    }
+    let mut status: Port<u8> = Port::new(KBC_STATUS);
+    unsafe {
+        for _ in 0..1_000_000 {
+            if status.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+            spin_loop_hint();
+        }
+        status.write(KBC_PULSE_RESET);
+    }
+    settle();
+    Err("still running after the keyboard controller reset")
+}
+
+/// With an empty IDT the breakpoint cannot be delivered, nor can the double
+/// fault that follows, and the cpu resets.
+fn triple_fault() -> ! {
+    use x86_64::structures::DescriptorTablePointer;
+    let empty = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+        llvm_asm!("int3" :::: "volatile");
+    }
+    halt()
+}
+
+/// Powers the machine off through ACPI S5, halting if that does not work.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    log::info!("powering off");
+    if let Err(reason) = acpi_power_off() {
+        log::warn!("acpi power off failed: {}", reason);
+    }
+    log::warn!("could not power off, halting");
+    halt()
+}
+
+/// Resets the machine through the ACPI reset register, then the keyboard
+/// controller, then by triple faulting.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    log::info!("rebooting");
+    if let Err(reason) = acpi_reset() {
+        log::warn!("acpi reset failed: {}", reason);
+    }
+    if let Err(reason) = keyboard_controller_reset() {
+        log::warn!("keyboard controller reset failed: {}", reason);
+    }
+    triple_fault()
+}
@@ -0,0 +1,69 @@
+use x86_64::VirtAddr;
+
+/// Callee-saved state of a switched-out thread. Everything else lives on the
+/// thread's own kernel stack, pushed by `__ngos_switch_context`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Context {
+    rsp: u64,
+}
+
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global __ngos_switch_context
+__ngos_switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, [rsi]
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+.att_syntax prefix
+"#
+);
+
+extern "C" {
+    fn __ngos_switch_context(prev: *mut Context, next: *const Context);
+}
+
+impl Context {
+    /// Context of a thread that is already running (i.e. the boot thread).
+    /// It gets filled in the first time the thread is switched out.
+    pub const fn empty() -> Context {
+        Context { rsp: 0 }
+    }
+
+    /// Builds the frame `__ngos_switch_context` expects, so that switching to
+    /// the new context "returns" into `entry` on a fresh stack.
+    pub fn new(stack_top: VirtAddr, entry: extern "C" fn() -> !) -> Context {
+        let top = stack_top.as_u64() & !0xf;
+        // r15, r14, r13, r12, rbx, rbp, return address, fake caller frame
+        let frame = (top - 8 * 8) as *mut u64;
+        unsafe {
+            for i in 0..6 {
+                *frame.add(i) = 0;
+            }
+            *frame.add(6) = entry as u64;
+            *frame.add(7) = 0;
+        }
+        Context { rsp: frame as u64 }
+    }
+}
+
+/// Saves the current callee-saved registers into `prev` and resumes `next`.
+///
+/// Must be called with interrupts disabled, and `next` must stay alive until
+/// the switch is done.
+pub unsafe fn switch(prev: *mut Context, next: *const Context) {
+    __ngos_switch_context(prev, next);
+}
@@ -1,3 +1,547 @@
-struct Context {
-    
-}
\ No newline at end of file
+mod context;
+mod policy;
+mod thread;
+
+pub use policy::{PolicyKind, SchedParam};
+pub use thread::{State, ThreadId};
+
+use super::memory::{
+    kernel_page_table, switch_page_table, AddressSpace, KernelStack, KERNEL_STACK_PAGES,
+};
+use super::smp;
+use crate::core_local;
+use crate::util::{init_cell::InitCell, mutex_int::MutexInt};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use context::Context;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use policy::Policy;
+use thread::Thread;
+use x86_64::{instructions::interrupts, structures::paging::PhysFrame, VirtAddr};
+
+/// What the scheduler keeps for each cpu, indexed by the cpu id.
+struct CpuSched {
+    current: ThreadId,
+    idle: ThreadId,
+    // the thread we just switched away from, finished on the new stack
+    prev: Option<ThreadId>,
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    policy: Box<dyn Policy>,
+    cpus: Vec<CpuSched>,
+    next_id: u64,
+}
+
+static SCHEDULER: InitCell<MutexInt<Scheduler>> = InitCell::new();
+
+core_local! {
+    // set by the timer, the thread on the cpu should give it up
+    static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+    // spinlocks held by the thread on the cpu
+    static PREEMPT_COUNT: Cell<usize> = Cell::new(0);
+}
+
+impl Scheduler {
+    /// The running cpu. The scheduler lock keeps interrupts off, so it
+    /// cannot change under us.
+    fn cpu(&mut self) -> &mut CpuSched {
+        let id = smp::current().id();
+        &mut self.cpus[id]
+    }
+
+    fn is_idle(&self, id: ThreadId) -> bool {
+        self.cpus.iter().any(|cpu| cpu.idle == id)
+    }
+
+    fn alloc_id(&mut self) -> ThreadId {
+        let id = ThreadId::from_u64(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn thread(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("no such thread")
+    }
+
+    fn current(&mut self) -> &mut Thread {
+        let id = self.cpu().current;
+        self.thread(id)
+    }
+
+    fn wake(&mut self, id: ThreadId) {
+        let thread = self.thread(id);
+        if thread.state != State::Blocked {
+            return;
+        }
+        thread.state = State::Ready;
+        // still switching out, `finish_switch` will queue it
+        if !thread.on_cpu {
+            self.policy.enqueue(id);
+            for (id, cpu) in self.cpus.iter().enumerate() {
+                if cpu.current == cpu.idle {
+                    NEED_RESCHED.on(id).store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Picks the next thread and returns what to switch between, or None if
+    /// the current thread keeps running.
+    fn pick_next(&mut self) -> Option<Switch> {
+        let prev = self.cpu().current;
+        let idle = self.cpu().idle;
+        let prev_runnable = match self.current().state {
+            State::Running | State::Ready => true,
+            State::Blocked | State::Dead => false,
+        };
+
+        let next = match self.policy.pick_next() {
+            Some(next) => next,
+            None if prev_runnable => prev,
+            None => idle,
+        };
+        if next == prev {
+            self.current().state = State::Running;
+            return None;
+        }
+
+        if prev_runnable {
+            self.current().state = State::Ready;
+        }
+        let cpu = self.cpu();
+        cpu.prev = Some(prev);
+        cpu.current = next;
+
+        let next_thread = self.thread(next);
+        next_thread.state = State::Running;
+        next_thread.on_cpu = true;
+        let next_ctx = &next_thread.context as *const Context;
+        let kernel_stack = next_thread.stack.as_ref().map(|stack| stack.top());
+        let page_table = next_thread
+            .addr_space
+            .as_ref()
+            .map_or_else(kernel_page_table, |space| space.l4_frame());
+        let prev_ctx = &mut self.thread(prev).context as *mut Context;
+        Some(Switch {
+            prev: prev_ctx,
+            next: next_ctx,
+            kernel_stack,
+            page_table,
+        })
+    }
+}
+
+struct Switch {
+    prev: *mut Context,
+    next: *const Context,
+    kernel_stack: Option<VirtAddr>,
+    page_table: PhysFrame,
+}
+
+/// Gives up the cpu. The current thread is put back to the run queue unless
+/// it marked itself blocked or dead.
+fn schedule() {
+    let int_en = interrupts::are_enabled();
+    // stay on this cpu until the switch
+    interrupts::disable();
+    assert!(
+        !super::is_interrupt_context(),
+        "cannot schedule in interrupt context"
+    );
+    assert_eq!(
+        PREEMPT_COUNT.with(|count| count.get()),
+        0,
+        "cannot schedule while holding a spinlock"
+    );
+    NEED_RESCHED.with(|resched| resched.store(false, Ordering::Relaxed));
+    let switch = SCHEDULER.lock().pick_next();
+    if let Some(switch) = switch {
+        if let Some(top) = switch.kernel_stack {
+            super::gdt::set_kernel_stack(top);
+            super::syscall::set_kernel_stack(top);
+        }
+        // the previous address space is only dropped in `finish_switch`,
+        // after it has been switched away from
+        switch_page_table(switch.page_table);
+        unsafe {
+            context::switch(switch.prev, switch.next);
+        }
+        finish_switch();
+    }
+    if int_en {
+        interrupts::enable();
+    }
+}
+
+/// Runs on the new stack right after a switch, once nothing executes on the
+/// previous thread's stack anymore.
+fn finish_switch() {
+    let mut dead_stack: Option<KernelStack> = None;
+    let mut dead_thread: Option<Box<Thread>> = None;
+    {
+        let mut sched = SCHEDULER.lock();
+        let prev = match sched.cpu().prev.take() {
+            Some(prev) => prev,
+            None => return,
+        };
+        let idle = sched.cpu().idle;
+        let thread = sched.thread(prev);
+        thread.on_cpu = false;
+        match thread.state {
+            State::Ready if prev != idle => sched.policy.enqueue(prev),
+            State::Dead => {
+                sched.policy.remove(prev);
+                let thread = sched.thread(prev);
+                if thread.detached {
+                    dead_thread = sched.threads.remove(&prev);
+                } else {
+                    dead_stack = thread.stack.take();
+                    if let Some(joiner) = thread.joiner {
+                        sched.wake(joiner);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // freeing memory takes other locks, do it outside of the scheduler
+    drop(dead_stack);
+    drop(dead_thread);
+}
+
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    interrupts::enable();
+    let entry = SCHEDULER
+        .lock()
+        .current()
+        .entry
+        .take()
+        .expect("thread started twice");
+    let code = entry();
+    exit(code)
+}
+
+/// Handle to a spawned thread. Dropping it detaches the thread.
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Waits for the thread to exit and returns its exit code.
+    pub fn join(self) -> i32 {
+        let id = self.id;
+        core::mem::forget(self);
+        loop {
+            {
+                let mut sched = SCHEDULER.lock();
+                let current = sched.cpu().current;
+                assert_ne!(id, current, "thread joining itself");
+                let thread = sched.thread(id);
+                if thread.state == State::Dead && !thread.on_cpu {
+                    let thread = sched.threads.remove(&id).unwrap();
+                    return thread.exit_code.unwrap();
+                }
+                thread.joiner = Some(current);
+                sched.current().state = State::Blocked;
+            }
+            schedule();
+        }
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let mut sched = SCHEDULER.lock();
+        let thread = sched.thread(self.id);
+        if thread.state == State::Dead && !thread.on_cpu {
+            sched.threads.remove(&self.id);
+        } else {
+            thread.detached = true;
+        }
+    }
+}
+
+/// Starts a new kernel thread running `f`. The return value of `f` becomes
+/// the exit code of the thread.
+pub fn spawn<F>(name: &'static str, f: F) -> JoinHandle
+where
+    F: FnOnce() -> i32 + Send + 'static,
+{
+    spawn_with(name, SchedParam::default(), f)
+}
+
+pub fn spawn_with<F>(name: &'static str, param: SchedParam, f: F) -> JoinHandle
+where
+    F: FnOnce() -> i32 + Send + 'static,
+{
+    spawn_thread(name, param, None, Box::new(f))
+}
+
+/// Starts a new thread running `f` in `addr_space`, typically one that goes
+/// on to enter user mode there.
+pub fn spawn_in<F>(name: &'static str, addr_space: Arc<AddressSpace>, f: F) -> JoinHandle
+where
+    F: FnOnce() -> i32 + Send + 'static,
+{
+    spawn_thread(name, SchedParam::default(), Some(addr_space), Box::new(f))
+}
+
+fn spawn_thread(
+    name: &'static str,
+    param: SchedParam,
+    addr_space: Option<Arc<AddressSpace>>,
+    entry: thread::Entry,
+) -> JoinHandle {
+    let stack = KernelStack::new(KERNEL_STACK_PAGES);
+    let mut sched = SCHEDULER.lock();
+    let id = sched.alloc_id();
+    let thread = Thread::new(id, name, param, stack, addr_space, entry);
+    log::trace!("spawning thread {:?} ({})", id, name);
+    sched.threads.insert(id, Box::new(thread));
+    sched.policy.add(id, param);
+    sched.policy.enqueue(id);
+    JoinHandle { id }
+}
+
+/// Exit code of a thread killed for an illegal instruction.
+pub const EXIT_ILL: i32 = 128 + 4;
+/// Exit code of a thread killed for a debug trap it did not ask the kernel
+/// to handle.
+pub const EXIT_TRAP: i32 = 128 + 5;
+/// Exit code of a thread killed for a misaligned access.
+pub const EXIT_BUS: i32 = 128 + 7;
+/// Exit code of a thread killed for an arithmetic error.
+pub const EXIT_FPE: i32 = 128 + 8;
+/// Exit code of a thread killed for an illegal memory access.
+pub const EXIT_SEGV: i32 = 128 + 11;
+
+/// Terminates the current thread.
+pub fn exit(code: i32) -> ! {
+    interrupts::disable();
+    {
+        let mut sched = SCHEDULER.lock();
+        let thread = sched.current();
+        log::trace!("thread {:?} ({}) exited with {}", thread.id, thread.name, code);
+        thread.state = State::Dead;
+        thread.exit_code = Some(code);
+    }
+    schedule();
+    unreachable!("dead thread scheduled again");
+}
+
+pub fn yield_now() {
+    schedule();
+}
+
+/// Blocks the current thread until somebody calls `unpark` on it. An
+/// `unpark` that comes first is not lost, `park` then returns right away.
+/// It may also return for no reason, so callers check their condition in a
+/// loop.
+pub fn park() {
+    {
+        let mut sched = SCHEDULER.lock();
+        let thread = sched.current();
+        if thread.unparked {
+            thread.unparked = false;
+            return;
+        }
+        thread.state = State::Blocked;
+    }
+    schedule();
+}
+
+/// Wakes `id` if it is parked, or makes its next `park` return right away.
+/// Works from interrupt context.
+pub fn unpark(id: ThreadId) {
+    let mut sched = SCHEDULER.lock();
+    let thread = sched.thread(id);
+    if thread.state == State::Blocked {
+        sched.wake(id);
+    } else {
+        thread.unparked = true;
+    }
+}
+
+pub fn current() -> ThreadId {
+    SCHEDULER.lock().cpu().current
+}
+
+/// Address space of the current thread, None for kernel threads.
+pub fn current_addr_space() -> Option<Arc<AddressSpace>> {
+    SCHEDULER.lock().current().addr_space.clone()
+}
+
+/// Moves the current thread into `addr_space` and switches to it. The old
+/// address space is released once nothing else uses it.
+pub fn set_addr_space(addr_space: Arc<AddressSpace>) {
+    let old = {
+        let mut sched = SCHEDULER.lock();
+        addr_space.activate();
+        sched.current().addr_space.replace(addr_space)
+    };
+    drop(old);
+}
+
+/// Called on every timer interrupt, in interrupt context.
+pub fn tick() {
+    if let Some(sched) = SCHEDULER.try_get() {
+        let mut sched = sched.lock();
+        // not yet taking part in scheduling
+        if smp::current().id() >= sched.cpus.len() {
+            return;
+        }
+        let cpu = sched.cpu();
+        let (current, idle) = (cpu.current, cpu.idle);
+        let resched = if current == idle {
+            sched.policy.has_ready()
+        } else {
+            sched.policy.tick(current)
+        };
+        if resched {
+            NEED_RESCHED.with(|resched| resched.store(true, Ordering::Relaxed));
+        }
+    }
+}
+
+/// Called on the way out of an interrupt, after leaving interrupt context.
+/// Does nothing if the interrupt came in while in another interrupt context.
+pub fn preempt() {
+    if SCHEDULER.try_get().is_some()
+        && !super::is_interrupt_context()
+        && PREEMPT_COUNT.with(|count| count.get()) == 0
+        && NEED_RESCHED.with(|resched| resched.load(Ordering::Relaxed))
+    {
+        schedule();
+    }
+}
+
+/// Keeps the current thread on its cpu until the matching `preempt_enable`.
+pub fn preempt_disable() {
+    PREEMPT_COUNT.with(|count| count.set(count.get() + 1));
+}
+
+pub fn preempt_enable() {
+    let prev = PREEMPT_COUNT.with(|count| count.replace(count.get().wrapping_sub(1)));
+    assert!(prev > 0, "unbalanced preempt_enable");
+}
+
+fn idle() -> i32 {
+    run_idle()
+}
+
+/// The idle loop, waits for interrupts and runs whatever became ready.
+pub fn run_idle() -> ! {
+    loop {
+        unsafe {
+            llvm_asm!("sti; hlt" :::: "volatile");
+        }
+        schedule();
+    }
+}
+
+/// Makes what runs on an application processor its idle thread, on `stack`.
+/// Application processors join in the order of their ids.
+pub fn init_ap(stack: KernelStack) {
+    let cpu = smp::current();
+    let mut sched = SCHEDULER.lock();
+    assert_eq!(sched.cpus.len(), cpu.id(), "cpus joined out of order");
+    let id = sched.alloc_id();
+    let mut thread = Thread::boot(id);
+    thread.name = "idle";
+    thread.stack = Some(stack);
+    sched.threads.insert(id, Box::new(thread));
+    sched.cpus.push(CpuSched {
+        current: id,
+        idle: id,
+        prev: None,
+    });
+}
+
+/// Replaces the scheduling policy, moving every thread over to the new one.
+/// Meant to be called once at boot, before the workload starts.
+pub fn set_policy(kind: PolicyKind) {
+    let mut sched = SCHEDULER.lock();
+    let mut policy = kind.create();
+    for thread in sched.threads.values() {
+        if sched.is_idle(thread.id) || thread.state == State::Dead {
+            continue;
+        }
+        policy.add(thread.id, thread.param);
+        if thread.state == State::Ready && !thread.on_cpu {
+            policy.enqueue(thread.id);
+        }
+    }
+    log::info!("scheduling policy: {}", policy.name());
+    sched.policy = policy;
+}
+
+pub fn init() {
+    crate::call_stack!();
+    let mut sched = Scheduler {
+        threads: BTreeMap::new(),
+        policy: PolicyKind::default().create(),
+        cpus: Vec::new(),
+        next_id: 0,
+    };
+
+    let boot = sched.alloc_id();
+    sched.threads.insert(boot, Box::new(Thread::boot(boot)));
+    sched.policy.add(boot, SchedParam::default());
+
+    let idle_id = sched.alloc_id();
+    let stack = KernelStack::new(KERNEL_STACK_PAGES);
+    let mut idle_thread = Thread::new(
+        idle_id,
+        "idle",
+        SchedParam::default(),
+        stack,
+        None,
+        Box::new(idle),
+    );
+    idle_thread.detached = true;
+    sched.threads.insert(idle_id, Box::new(idle_thread));
+    sched.cpus.push(CpuSched {
+        current: boot,
+        idle: idle_id,
+        prev: None,
+    });
+
+    SCHEDULER.init(MutexInt::new(true, sched));
+}
+
+#[test_case]
+fn spawn_and_join() {
+    let handles: Vec<JoinHandle> = (1..=3).map(|i| spawn("test", move || i)).collect();
+    let sum: i32 = handles.into_iter().map(|handle| handle.join()).sum();
+    assert_eq!(sum, 6);
+}
+
+#[test_case]
+fn threads_are_preempted() {
+    // the spinning thread never yields, so the other one can only make
+    // progress if the timer takes the cpu away
+    let flag = Arc::new(AtomicBool::new(false));
+    let spinner = {
+        let flag = flag.clone();
+        spawn("spinner", move || {
+            while !flag.load(Ordering::SeqCst) {}
+            0
+        })
+    };
+    let setter = {
+        let flag = flag.clone();
+        spawn("setter", move || {
+            flag.store(true, Ordering::SeqCst);
+            0
+        })
+    };
+    assert_eq!(spinner.join(), 0);
+    assert_eq!(setter.join(), 0);
+}
@@ -0,0 +1,160 @@
+use super::{Policy, SchedParam, ThreadId};
+use alloc::{collections::BTreeMap, vec::Vec};
+
+const TICK_VRUNTIME: u64 = 1_000_000;
+const NICE_0_WEIGHT: u64 = SchedParam::DEFAULT_WEIGHT as u64;
+/// How far the running thread may get ahead of the leftmost one.
+const GRANULARITY: u64 = TICK_VRUNTIME;
+/// Credit a waking thread keeps, so sleepers do not starve everybody else
+/// once they come back.
+const SLEEPER_CREDIT: u64 = 3 * TICK_VRUNTIME;
+
+struct Entity {
+    vruntime: u64,
+    weight: u64,
+}
+
+/// Virtual-runtime based fair share, in the spirit of CFS. Each thread
+/// accumulates runtime scaled by the inverse of its weight, and the one that
+/// has received the least so far runs next.
+pub struct Fair {
+    entities: BTreeMap<ThreadId, Entity>,
+    // sorted, with room for every entity so enqueueing never allocates
+    ready: Vec<(u64, ThreadId)>,
+    min_vruntime: u64,
+}
+
+impl Fair {
+    pub fn new() -> Fair {
+        Fair {
+            entities: BTreeMap::new(),
+            ready: Vec::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    fn leftmost(&self) -> Option<u64> {
+        self.ready.first().map(|&(vruntime, _)| vruntime)
+    }
+}
+
+impl Policy for Fair {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn add(&mut self, id: ThreadId, param: SchedParam) {
+        assert!(param.weight > 0, "zero weight");
+        self.entities.insert(
+            id,
+            Entity {
+                vruntime: self.min_vruntime,
+                weight: param.weight as u64,
+            },
+        );
+        self.ready.reserve(self.entities.len() - self.ready.len());
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        if let Some(entity) = self.entities.remove(&id) {
+            if let Ok(index) = self.ready.binary_search(&(entity.vruntime, id)) {
+                self.ready.remove(index);
+            }
+        }
+    }
+
+    fn enqueue(&mut self, id: ThreadId) {
+        let floor = self.min_vruntime.saturating_sub(SLEEPER_CREDIT);
+        let entity = self.entities.get_mut(&id).unwrap();
+        entity.vruntime = core::cmp::max(entity.vruntime, floor);
+        let key = (entity.vruntime, id);
+        if let Err(index) = self.ready.binary_search(&key) {
+            self.ready.insert(index, key);
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        if self.ready.is_empty() {
+            return None;
+        }
+        let (vruntime, id) = self.ready.remove(0);
+        self.min_vruntime = core::cmp::max(self.min_vruntime, vruntime);
+        Some(id)
+    }
+
+    fn has_ready(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    fn tick(&mut self, current: ThreadId) -> bool {
+        let entity = self.entities.get_mut(&current).unwrap();
+        entity.vruntime += TICK_VRUNTIME * NICE_0_WEIGHT / entity.weight;
+        let vruntime = entity.vruntime;
+
+        match self.leftmost() {
+            Some(leftmost) => {
+                self.min_vruntime =
+                    core::cmp::max(self.min_vruntime, core::cmp::min(vruntime, leftmost));
+                vruntime > leftmost + GRANULARITY
+            }
+            None => {
+                self.min_vruntime = core::cmp::max(self.min_vruntime, vruntime);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn with_weights(weights: &[u32]) -> Fair {
+    let mut policy = Fair::new();
+    for (i, &weight) in weights.iter().enumerate() {
+        let id = ThreadId::from_u64(i as u64);
+        policy.add(
+            id,
+            SchedParam {
+                weight,
+                ..Default::default()
+            },
+        );
+        policy.enqueue(id);
+    }
+    policy
+}
+
+#[test_case]
+fn fair_equal_share() {
+    let mut policy = with_weights(&[1024, 1024, 1024]);
+    let runtime = super::simulate(&mut policy, 3, 3000);
+    for &ticks in runtime.iter() {
+        assert!(ticks >= 990 && ticks <= 1010, "unfair share {}", ticks);
+    }
+}
+
+#[test_case]
+fn fair_weighted_share() {
+    let mut policy = with_weights(&[1024, 2048]);
+    let runtime = super::simulate(&mut policy, 2, 3000);
+    assert!(runtime[0] >= 990 && runtime[0] <= 1010, "unfair share {}", runtime[0]);
+    assert!(runtime[1] >= 1990 && runtime[1] <= 2010, "unfair share {}", runtime[1]);
+}
+
+#[test_case]
+fn fair_sleeper_does_not_starve_others() {
+    let mut policy = with_weights(&[1024, 1024]);
+    let sleeper = ThreadId::from_u64(2);
+    policy.add(sleeper, SchedParam::default());
+
+    // let the others build up a lot of runtime while the sleeper is away
+    super::simulate(&mut policy, 3, 1000);
+    policy.enqueue(sleeper);
+    assert_eq!(policy.pick_next(), Some(sleeper));
+
+    // the sleeper only keeps a bounded credit, so the others get the cpu back
+    // after a few ticks
+    let mut ticks = 0;
+    while !policy.tick(sleeper) {
+        ticks += 1;
+        assert!(ticks < 10, "sleeper starves the other threads");
+    }
+}
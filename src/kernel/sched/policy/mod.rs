@@ -0,0 +1,103 @@
+mod fair;
+mod priority;
+mod round_robin;
+
+pub use fair::Fair;
+pub use priority::FixedPriority;
+pub use round_robin::RoundRobin;
+
+use super::ThreadId;
+use alloc::boxed::Box;
+
+/// Timer ticks a thread may run before it gets preempted in favor of an
+/// equally important one.
+pub const TIME_SLICE_TICKS: u64 = 2;
+
+/// Per-thread knobs, each policy only looks at the ones it cares about.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SchedParam {
+    /// Used by `FixedPriority`, higher runs first.
+    pub priority: u8,
+    /// Used by `Fair`, cpu share is proportional to it.
+    pub weight: u32,
+}
+
+impl SchedParam {
+    pub const DEFAULT_PRIORITY: u8 = 8;
+    pub const DEFAULT_WEIGHT: u32 = 1024;
+}
+
+impl Default for SchedParam {
+    fn default() -> Self {
+        SchedParam {
+            priority: Self::DEFAULT_PRIORITY,
+            weight: Self::DEFAULT_WEIGHT,
+        }
+    }
+}
+
+/// Decides which ready thread runs next. The scheduler core owns the threads
+/// and their contexts, a policy only ever sees ids.
+///
+/// `enqueue` and `tick` may run in interrupt context and must not allocate:
+/// `add` sets aside whatever room enqueueing the thread takes.
+pub trait Policy: Send {
+    fn name(&self) -> &'static str;
+
+    /// A thread joined the scheduler. It is not runnable until enqueued.
+    fn add(&mut self, id: ThreadId, param: SchedParam);
+
+    /// A thread left the scheduler for good.
+    fn remove(&mut self, id: ThreadId);
+
+    /// The thread became runnable.
+    fn enqueue(&mut self, id: ThreadId);
+
+    /// Takes the thread that should run next out of the ready set.
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    fn has_ready(&self) -> bool;
+
+    /// Accounts one timer tick to the running thread. Returns true if it
+    /// should be preempted.
+    fn tick(&mut self, current: ThreadId) -> bool;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PolicyKind {
+    RoundRobin,
+    FixedPriority,
+    Fair,
+}
+
+impl Default for PolicyKind {
+    fn default() -> Self {
+        PolicyKind::RoundRobin
+    }
+}
+
+impl PolicyKind {
+    pub fn create(self) -> Box<dyn Policy> {
+        match self {
+            PolicyKind::RoundRobin => Box::new(RoundRobin::new()),
+            PolicyKind::FixedPriority => Box::new(FixedPriority::new()),
+            PolicyKind::Fair => Box::new(Fair::new()),
+        }
+    }
+}
+
+/// Runs `policy` for `ticks` timer ticks as if every thread were cpu bound,
+/// and returns how many ticks each thread got, indexed by thread id.
+#[cfg(test)]
+fn simulate(policy: &mut dyn Policy, threads: u64, ticks: u64) -> alloc::vec::Vec<u64> {
+    let mut runtime = alloc::vec![0; threads as usize];
+    let mut current = policy.pick_next().expect("nothing to run");
+    for _ in 0..ticks {
+        runtime[current.as_u64() as usize] += 1;
+        if policy.tick(current) {
+            policy.enqueue(current);
+            current = policy.pick_next().expect("nothing to run");
+        }
+    }
+    runtime
+}
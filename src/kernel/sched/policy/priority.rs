@@ -0,0 +1,132 @@
+use super::{Policy, SchedParam, ThreadId, TIME_SLICE_TICKS};
+use alloc::collections::{BTreeMap, VecDeque};
+
+/// Always runs the highest priority ready thread, round-robin among threads
+/// of the same priority. Lower priorities starve while higher ones are ready.
+pub struct FixedPriority {
+    priorities: BTreeMap<ThreadId, u8>,
+    queues: BTreeMap<u8, VecDeque<ThreadId>>,
+    // ticks each thread has run for since it was last picked, kept per thread
+    // as every cpu runs one
+    slices: BTreeMap<ThreadId, u64>,
+}
+
+impl FixedPriority {
+    pub fn new() -> FixedPriority {
+        FixedPriority {
+            priorities: BTreeMap::new(),
+            queues: BTreeMap::new(),
+            slices: BTreeMap::new(),
+        }
+    }
+
+    fn highest_ready(&self) -> Option<u8> {
+        self.queues
+            .iter()
+            .rev()
+            .find(|(_, queue)| !queue.is_empty())
+            .map(|(&priority, _)| priority)
+    }
+}
+
+impl Policy for FixedPriority {
+    fn name(&self) -> &'static str {
+        "fixed-priority"
+    }
+
+    fn add(&mut self, id: ThreadId, param: SchedParam) {
+        self.priorities.insert(id, param.priority);
+        self.slices.insert(id, 0);
+        let threads = self
+            .priorities
+            .values()
+            .filter(|&&priority| priority == param.priority)
+            .count();
+        let queue = self.queues.entry(param.priority).or_insert_with(VecDeque::new);
+        queue.reserve(threads - queue.len());
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.slices.remove(&id);
+        if let Some(priority) = self.priorities.remove(&id) {
+            if let Some(queue) = self.queues.get_mut(&priority) {
+                queue.retain(|&queued| queued != id);
+            }
+        }
+    }
+
+    fn enqueue(&mut self, id: ThreadId) {
+        let priority = self.priorities[&id];
+        self.queues.get_mut(&priority).unwrap().push_back(id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let priority = self.highest_ready()?;
+        let next = self.queues.get_mut(&priority).unwrap().pop_front()?;
+        *self.slices.get_mut(&next).unwrap() = 0;
+        Some(next)
+    }
+
+    fn has_ready(&self) -> bool {
+        self.highest_ready().is_some()
+    }
+
+    fn tick(&mut self, current: ThreadId) -> bool {
+        let slice = self.slices.get_mut(&current).unwrap();
+        *slice += 1;
+        let slice = *slice;
+        let current = self.priorities[&current];
+        match self.highest_ready() {
+            Some(best) if best > current => true,
+            Some(best) if best == current => slice >= TIME_SLICE_TICKS,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+fn with_priorities(priorities: &[u8]) -> FixedPriority {
+    let mut policy = FixedPriority::new();
+    for (i, &priority) in priorities.iter().enumerate() {
+        let id = ThreadId::from_u64(i as u64);
+        policy.add(
+            id,
+            SchedParam {
+                priority,
+                ..Default::default()
+            },
+        );
+        policy.enqueue(id);
+    }
+    policy
+}
+
+#[test_case]
+fn priority_runs_highest_first() {
+    // the low priority thread gets nothing while the others are ready
+    let mut policy = with_priorities(&[1, 5, 5]);
+    let runtime = super::simulate(&mut policy, 3, 100);
+    assert_eq!(runtime[0], 0);
+    assert_eq!(runtime[1], 50);
+    assert_eq!(runtime[2], 50);
+}
+
+#[test_case]
+fn priority_preempts_on_wakeup() {
+    let mut policy = with_priorities(&[1]);
+    let low = policy.pick_next().unwrap();
+    assert!(!policy.tick(low));
+
+    let high = ThreadId::from_u64(1);
+    policy.add(
+        high,
+        SchedParam {
+            priority: 9,
+            ..Default::default()
+        },
+    );
+    policy.enqueue(high);
+    assert!(policy.tick(low));
+    policy.enqueue(low);
+    assert_eq!(policy.pick_next(), Some(high));
+}
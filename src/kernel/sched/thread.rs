@@ -0,0 +1,99 @@
+use super::context::Context;
+use super::SchedParam;
+use crate::kernel::memory::{AddressSpace, KernelStack};
+use alloc::{boxed::Box, sync::Arc};
+
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    pub(super) fn from_u64(id: u64) -> Self {
+        ThreadId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    /// Currently on a cpu.
+    Running,
+    /// Runnable, waiting in the run queue (or about to be put back there).
+    Ready,
+    /// Waiting for somebody to wake it up.
+    Blocked,
+    /// Exited, waiting to be joined.
+    Dead,
+}
+
+pub type Entry = Box<dyn FnOnce() -> i32 + Send + 'static>;
+
+pub struct Thread {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    pub param: SchedParam,
+    pub context: Context,
+    /// None for the boot thread, which runs on the bootloader stack.
+    pub stack: Option<KernelStack>,
+    /// None for kernel threads, which run on the boot page table.
+    pub addr_space: Option<Arc<AddressSpace>>,
+    pub entry: Option<Entry>,
+    pub exit_code: Option<i32>,
+    pub joiner: Option<ThreadId>,
+    pub detached: bool,
+    /// `unpark` came while the thread was not parked, the next `park`
+    /// returns right away.
+    pub unparked: bool,
+    /// Still executing on its stack; set from the moment it is picked until
+    /// the next thread finished switching away from it.
+    pub on_cpu: bool,
+}
+
+impl Thread {
+    pub fn new(
+        id: ThreadId,
+        name: &'static str,
+        param: SchedParam,
+        stack: KernelStack,
+        addr_space: Option<Arc<AddressSpace>>,
+        entry: Entry,
+    ) -> Thread {
+        let context = Context::new(stack.top(), super::thread_start);
+        Thread {
+            id,
+            name,
+            state: State::Ready,
+            param,
+            context,
+            stack: Some(stack),
+            addr_space,
+            entry: Some(entry),
+            exit_code: None,
+            joiner: None,
+            detached: false,
+            unparked: false,
+            on_cpu: false,
+        }
+    }
+
+    pub fn boot(id: ThreadId) -> Thread {
+        Thread {
+            id,
+            name: "boot",
+            state: State::Running,
+            param: SchedParam::default(),
+            context: Context::empty(),
+            stack: None,
+            addr_space: None,
+            entry: None,
+            exit_code: None,
+            joiner: None,
+            detached: true,
+            unparked: false,
+            on_cpu: true,
+        }
+    }
+}
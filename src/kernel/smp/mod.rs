@@ -0,0 +1,255 @@
+mod trampoline;
+
+use super::irqchip::{self, Ipi, IpiTarget};
+use super::memory::{KernelStack, KERNEL_STACK_PAGES};
+use super::time::pit_delay_us;
+use crate::util::mutex_int::MutexInt;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use trampoline::Trampoline;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Cpus beyond this are left alone. `core_local!` writes out one initializer
+/// per cpu, keep the two in step.
+pub const MAX_CPUS: usize = 16;
+
+/// Vector of the IPI the boot cpu forwards its timer ticks with.
+pub const TICK_VECTOR: u8 = 0xf0;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// The per-cpu area. While in the kernel the GS base points at the running
+/// cpu's; entering from user mode swaps it in with `swapgs`, and leaving
+/// swaps the user's back. Values declared with `core_local!` hang off its
+/// id.
+#[repr(C)]
+pub struct Cpu {
+    // read through GS at fixed offsets, by `current` and the syscall entry
+    // stub, keep them first
+    this: AtomicPtr<Cpu>,
+    syscall_rsp: UnsafeCell<u64>,
+    user_rsp: UnsafeCell<u64>,
+    id: usize,
+    apic_id: AtomicU32,
+    online: AtomicBool,
+    tss: AtomicPtr<TaskStateSegment>,
+}
+
+// everything but the atomics is only touched by the cpu itself
+unsafe impl Sync for Cpu {}
+
+impl Cpu {
+    const fn new(id: usize) -> Cpu {
+        Cpu {
+            this: AtomicPtr::new(core::ptr::null_mut()),
+            syscall_rsp: UnsafeCell::new(0),
+            user_rsp: UnsafeCell::new(0),
+            id,
+            apic_id: AtomicU32::new(0),
+            online: AtomicBool::new(false),
+            tss: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Index of the cpu, the boot cpu is 0 and the others follow in the
+    /// order they came up.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn tss(&self) -> *mut TaskStateSegment {
+        self.tss.load(Ordering::Relaxed)
+    }
+
+    pub fn set_tss(&self, tss: *mut TaskStateSegment) {
+        self.tss.store(tss, Ordering::Relaxed);
+    }
+
+    /// Sets the stack `syscall` lands on. Only the cpu itself may call it.
+    pub fn set_syscall_stack(&self, top: VirtAddr) {
+        unsafe {
+            *self.syscall_rsp.get() = top.as_u64();
+        }
+    }
+}
+
+static BOOT_CPU: Cpu = Cpu::new(0);
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+// the stack of the cpu being started, it becomes the stack of its idle thread
+static AP_STACK: MutexInt<Option<KernelStack>> = MutexInt::new(true, None);
+
+/// The cpu this runs on. Unless interrupts are off, the thread may have
+/// moved elsewhere by the time the result is used.
+pub fn current() -> &'static Cpu {
+    let cpu: *const Cpu;
+    unsafe {
+        llvm_asm!("mov $0, gs:[0]" : "=r"(cpu) ::: "intel");
+        &*cpu
+    }
+}
+
+/// Index of the cpu this runs on, see `current`.
+pub fn current_id() -> usize {
+    interrupts::without_interrupts(|| current().id)
+}
+
+/// Number of cpus online.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+fn install(cpu: &'static Cpu) {
+    let ptr = cpu as *const Cpu;
+    cpu.this.store(ptr as *mut Cpu, Ordering::Relaxed);
+    unsafe {
+        Msr::new(IA32_GS_BASE).write(ptr as u64);
+        // what user mode starts out with
+        Msr::new(IA32_KERNEL_GS_BASE).write(0);
+    }
+}
+
+/// Makes `current` work on the boot cpu, before anything else runs.
+pub fn init_boot_cpu() {
+    install(&BOOT_CPU);
+}
+
+/// Passes a timer tick of the boot cpu on to the others, which have no timer
+/// of their own.
+pub fn broadcast_tick() {
+    if cpu_count() > 1 {
+        irqchip::send_ipi(IpiTarget::AllButSelf, Ipi::Fixed(TICK_VECTOR));
+    }
+}
+
+extern "C" fn ap_entry(cpu: &'static Cpu) -> ! {
+    install(cpu);
+    super::gdt::init_ap();
+    super::int::init_ap();
+    super::syscall::init_ap();
+    super::irqchip::init_ap();
+    let stack = AP_STACK.lock().take().expect("no stack for the cpu");
+    super::sched::init_ap(stack);
+    cpu.online.store(true, Ordering::Release);
+    super::sched::run_idle()
+}
+
+/// Starts the cpu with local APIC `apic_id` as cpu number `id` and waits for
+/// it to come up.
+fn start_ap(trampoline: &mut Trampoline, id: usize, apic_id: u32) -> bool {
+    let cpu: &'static Cpu = Box::leak(Box::new(Cpu::new(id)));
+    cpu.apic_id.store(apic_id, Ordering::Relaxed);
+    let stack = KernelStack::new(KERNEL_STACK_PAGES);
+    trampoline.prepare(stack.top(), ap_entry, cpu);
+    *AP_STACK.lock() = Some(stack);
+
+    let target = IpiTarget::Apic(apic_id);
+    irqchip::send_ipi(target, Ipi::Init);
+    pit_delay_us(10_000);
+    for _ in 0..2 {
+        irqchip::send_ipi(target, Ipi::Startup(trampoline.vector()));
+        pit_delay_us(200);
+        if cpu.online.load(Ordering::Acquire) {
+            break;
+        }
+    }
+    for _ in 0..1000 {
+        if cpu.online.load(Ordering::Acquire) {
+            return true;
+        }
+        pit_delay_us(1000);
+    }
+    false
+}
+
+/// Brings up every other cpu the MADT lists. They join the scheduler as
+/// soon as they are up.
+pub fn init() {
+    crate::call_stack!();
+    let boot_apic_id = match irqchip::local_apic_id() {
+        Some(id) => id,
+        None => {
+            log::info!("no local apic, running on the boot cpu only");
+            return;
+        }
+    };
+    BOOT_CPU.apic_id.store(boot_apic_id, Ordering::Relaxed);
+    BOOT_CPU.online.store(true, Ordering::Relaxed);
+    let madt = match super::acpi::madt() {
+        Some(madt) => madt,
+        None => {
+            log::info!("no madt, running on the boot cpu only");
+            return;
+        }
+    };
+    let frame = match super::memory::low_frame() {
+        Some(frame) => frame,
+        None => {
+            log::warn!("no memory below 1M for the startup trampoline");
+            return;
+        }
+    };
+
+    let mut trampoline = Trampoline::install(frame);
+    for processor in madt
+        .processors
+        .iter()
+        .filter(|p| p.enabled && p.apic_id != boot_apic_id)
+    {
+        let id = cpu_count();
+        if id == MAX_CPUS {
+            log::warn!("more than {} cpus, ignoring the rest", MAX_CPUS);
+            break;
+        }
+        // xAPIC destinations are 8 bits
+        if processor.apic_id > 0xff {
+            log::warn!("cannot start cpu with apic id {}", processor.apic_id);
+            continue;
+        }
+        if !start_ap(&mut trampoline, id, processor.apic_id) {
+            // it may still come up later and use the stack and id, so leave
+            // both alone and stop here
+            log::warn!("cpu with apic id {} did not start", processor.apic_id);
+            break;
+        }
+        CPU_COUNT.store(id + 1, Ordering::Release);
+        log::info!("cpu {} (apic id {}) online", id, processor.apic_id);
+    }
+    drop(trampoline);
+    log::info!("{} cpus online", cpu_count());
+}
+
+#[test_case]
+fn threads_run_on_every_cpu() {
+    use alloc::{sync::Arc, vec::Vec};
+    use core::sync::atomic::AtomicU64;
+
+    // every thread spins until each cpu has run one of them, which only
+    // ends if all cpus take part in scheduling
+    let all = (1u64 << cpu_count()) - 1;
+    let seen = Arc::new(AtomicU64::new(0));
+    let handles: Vec<_> = (0..cpu_count())
+        .map(|_| {
+            let seen = seen.clone();
+            super::sched::spawn("smp", move || loop {
+                let bit = 1 << current_id();
+                if seen.fetch_or(bit, Ordering::SeqCst) | bit == all {
+                    return 0;
+                }
+                core::sync::atomic::spin_loop_hint();
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join(), 0);
+    }
+    assert_eq!(seen.load(Ordering::SeqCst), all);
+}
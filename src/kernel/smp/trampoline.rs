@@ -0,0 +1,181 @@
+use crate::kernel::memory::{identity_map, identity_unmap, kernel_page_table, phys_to_virt};
+use super::Cpu;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+// Application processors start here in real mode, at the start of the frame
+// the code was copied to. It switches to long mode on the kernel page table
+// through a throwaway GDT and calls `entry(cpu)` on `stack`. The frame must
+// be identity mapped while it runs, the instruction after turning paging on
+// is fetched from the same address.
+global_asm!(
+    r#"
+    .code16
+    .global ngos_ap_trampoline
+ngos_ap_trampoline:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+    xorl %ebx, %ebx
+    movw %ax, %bx
+    shll $4, %ebx
+
+    # the code runs wherever it was copied to, fix up the absolute addresses
+    leal (trampoline_gdt - ngos_ap_trampoline)(%ebx), %eax
+    movl %eax, (trampoline_gdtr - ngos_ap_trampoline + 2)
+    leal (trampoline_32 - ngos_ap_trampoline)(%ebx), %eax
+    movl %eax, (trampoline_jump_32 - ngos_ap_trampoline)
+    leal (trampoline_64 - ngos_ap_trampoline)(%ebx), %eax
+    movl %eax, (trampoline_jump_64 - ngos_ap_trampoline)
+
+    lgdtl (trampoline_gdtr - ngos_ap_trampoline)
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl *(trampoline_jump_32 - ngos_ap_trampoline)
+
+    .code32
+trampoline_32:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    # PAE
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl (trampoline_cr3 - ngos_ap_trampoline)(%ebx), %eax
+    movl %eax, %cr3
+    # EFER.LME | EFER.NXE, the kernel maps data no-execute
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    # PG | WP
+    movl %cr0, %eax
+    orl $((1 << 31) | (1 << 16)), %eax
+    movl %eax, %cr0
+    ljmpl *(trampoline_jump_64 - ngos_ap_trampoline)(%ebx)
+
+    .code64
+trampoline_64:
+    movl %ebx, %ebx
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movq (trampoline_stack - ngos_ap_trampoline)(%rbx), %rsp
+    movq (trampoline_cpu - ngos_ap_trampoline)(%rbx), %rdi
+    movq (trampoline_entry - ngos_ap_trampoline)(%rbx), %rax
+    callq *%rax
+    ud2
+
+    .balign 8
+trampoline_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff # 0x08: 32 bit code
+    .quad 0x00cf92000000ffff # 0x10: data
+    .quad 0x00af9a000000ffff # 0x18: 64 bit code
+trampoline_gdtr:
+    .word 4 * 8 - 1
+    .long 0
+trampoline_jump_32:
+    .long 0
+    .word 0x08
+trampoline_jump_64:
+    .long 0
+    .word 0x18
+
+    .balign 8
+    .global ngos_ap_trampoline_params
+ngos_ap_trampoline_params:
+trampoline_cr3:
+    .quad 0
+trampoline_stack:
+    .quad 0
+trampoline_entry:
+    .quad 0
+trampoline_cpu:
+    .quad 0
+    .global ngos_ap_trampoline_end
+ngos_ap_trampoline_end:
+"#
+);
+
+extern "C" {
+    static ngos_ap_trampoline: u8;
+    static ngos_ap_trampoline_params: u8;
+    static ngos_ap_trampoline_end: u8;
+}
+
+/// Filled in before each startup IPI, laid out like the end of the code.
+#[repr(C)]
+struct Params {
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+/// The trampoline, copied into a frame below 1M.
+pub struct Trampoline {
+    frame: PhysFrame,
+    params: *mut Params,
+    unmap: bool,
+}
+
+impl Trampoline {
+    pub fn install(frame: PhysFrame) -> Trampoline {
+        let (code, params_offset) = unsafe {
+            let start = &ngos_ap_trampoline as *const u8;
+            let params = &ngos_ap_trampoline_params as *const u8 as usize - start as usize;
+            let len = &ngos_ap_trampoline_end as *const u8 as usize - start as usize;
+            (core::slice::from_raw_parts(start, len), params)
+        };
+        let dst = phys_to_virt(frame.start_address());
+        unsafe {
+            core::ptr::copy_nonoverlapping(code.as_ptr(), dst.as_mut_ptr::<u8>(), code.len());
+        }
+
+        let cr3 = kernel_page_table().start_address().as_u64();
+        // the trampoline loads it in 32 bit mode
+        assert!(cr3 < 1 << 32, "kernel page table above 4G");
+        let params = (dst + params_offset).as_mut_ptr::<Params>();
+        unsafe {
+            (*params).cr3 = cr3;
+        }
+        Trampoline {
+            frame,
+            params,
+            unmap: identity_map(frame),
+        }
+    }
+
+    /// Vector of the startup IPI, the page the code is in.
+    pub fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// Sets up the next cpu to start to call `entry(cpu)` on `stack`.
+    pub fn prepare(
+        &mut self,
+        stack: VirtAddr,
+        entry: extern "C" fn(&'static Cpu) -> !,
+        cpu: &'static Cpu,
+    ) {
+        unsafe {
+            (*self.params).stack = stack.as_u64();
+            (*self.params).entry = entry as u64;
+            (*self.params).cpu = cpu as *const Cpu as u64;
+        }
+    }
+}
+
+impl Drop for Trampoline {
+    fn drop(&mut self) {
+        if self.unmap {
+            identity_unmap(self.frame);
+        }
+    }
+}
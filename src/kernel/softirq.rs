@@ -0,0 +1,62 @@
+use super::int::{is_interrupt_context, ContextKind, InterruptContextHandle};
+use crate::core_local;
+use crate::util::mutex_int::MutexInt;
+use core::cell::Cell;
+use x86_64::instructions::interrupts;
+
+/// Kinds of deferred interrupt work. Each has one handler, run on the cpu it
+/// was raised on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SoftIrq {
+    /// Timer callbacks, raised by the timer interrupt.
+    Timer,
+}
+
+const NR_SOFTIRQS: usize = 1;
+
+/// Rounds of newly raised softirqs handled on one interrupt exit, the rest
+/// waits for the next one so threads are not starved.
+const MAX_RESTART: usize = 10;
+
+static HANDLERS: MutexInt<[Option<fn()>; NR_SOFTIRQS]> = MutexInt::new(true, [None; NR_SOFTIRQS]);
+
+core_local! {
+    static PENDING: Cell<u32> = Cell::new(0);
+}
+
+/// Sets the handler of `softirq`. It runs with interrupts on but in
+/// interrupt context, so it must not block.
+pub fn open_softirq(softirq: SoftIrq, handler: fn()) {
+    HANDLERS.lock()[softirq as usize] = Some(handler);
+}
+
+/// Marks `softirq` to run on this cpu on the way out of the next interrupt.
+pub fn raise_softirq(softirq: SoftIrq) {
+    PENDING.with(|pending| pending.set(pending.get() | 1 << softirq as u32));
+}
+
+/// Runs the pending softirqs of this cpu. Called with interrupts off on the
+/// way out of a hardware interrupt, once it left hard interrupt context; if
+/// it interrupted another interrupt context, that one's exit does it.
+pub fn do_softirq() {
+    if is_interrupt_context() {
+        return;
+    }
+    let _int = InterruptContextHandle::new(ContextKind::SoftIrq);
+    for _ in 0..MAX_RESTART {
+        let pending = PENDING.with(|pending| pending.replace(0));
+        if pending == 0 {
+            break;
+        }
+        let handlers = *HANDLERS.lock();
+        interrupts::enable();
+        for (nr, handler) in handlers.iter().enumerate() {
+            if pending & (1 << nr) != 0 {
+                if let Some(handler) = handler {
+                    handler();
+                }
+            }
+        }
+        interrupts::disable();
+    }
+}
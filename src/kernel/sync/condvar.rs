@@ -0,0 +1,106 @@
+use super::{assert_may_block, MutexGuard, WaitQueue};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Lets threads holding a `Mutex` sleep until another one signals that the
+/// data changed. Wakeups may be spurious, so waiters check what they are
+/// waiting for in a loop, or use `wait_while`.
+pub struct Condvar {
+    // bumped by every notify, a waiter is done once it changed
+    seq: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            seq: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex of `guard`, sleeps until notified and locks it
+    /// again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_timeout(guard, None).0
+    }
+
+    /// Waits for as long as `condition` holds on the data.
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Like `wait`, but gives up after `timeout` if given. Also returns
+    /// whether it was notified.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        assert_may_block("Condvar::wait");
+        // read before unlocking, so a notify right after is not missed
+        let seq = self.seq.load(Ordering::Acquire);
+        let mutex = guard.mutex();
+        drop(guard);
+        let notified = || self.seq.load(Ordering::Acquire) != seq;
+        let notified = match timeout {
+            Some(timeout) => self.waiters.wait_until_timeout(notified, timeout),
+            None => {
+                self.waiters.wait_until(notified);
+                true
+            }
+        };
+        (mutex.lock(), notified)
+    }
+
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
+
+#[test_case]
+fn condvar_hands_data_between_threads() {
+    use super::Mutex;
+    use crate::kernel::sched;
+    static QUEUE: Mutex<Option<u32>> = Mutex::new(None);
+    static CHANGED: Condvar = Condvar::new();
+
+    let consumer = sched::spawn("consumer", || {
+        let mut queue = CHANGED.wait_while(QUEUE.lock(), |queue| queue.is_none());
+        let value = queue.take().unwrap();
+        CHANGED.notify_all();
+        value as i32
+    });
+    *QUEUE.lock() = Some(42);
+    CHANGED.notify_all();
+    let queue = CHANGED.wait_while(QUEUE.lock(), |queue| queue.is_some());
+    assert!(queue.is_none());
+    drop(queue);
+    assert_eq!(consumer.join(), 42);
+
+    let timeout = Some(Duration::from_millis(2));
+    let (_queue, notified) = CHANGED.wait_timeout(QUEUE.lock(), timeout);
+    assert!(!notified);
+}
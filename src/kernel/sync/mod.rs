@@ -0,0 +1,22 @@
+mod condvar;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
+
+/// Catches a sleeping lock taken in interrupt context, where we must not
+/// block or tie the lock to whatever thread was interrupted.
+fn assert_may_block(what: &str) {
+    debug_assert!(
+        !crate::kernel::is_interrupt_context(),
+        "{} in interrupt context ({})",
+        what,
+        crate::kernel::interrupt_depth()
+    );
+}
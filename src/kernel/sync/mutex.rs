@@ -0,0 +1,148 @@
+use super::{assert_may_block, WaitQueue};
+use crate::kernel::sched::{self, ThreadId};
+use crate::util::mutex_int::MutexInt;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+/// A lock that puts threads waiting for it to sleep, for data held across
+/// anything slow. It knows its owner: locking it again from the same thread
+/// panics instead of deadlocking.
+pub struct Mutex<T> {
+    owner: MutexInt<Option<ThreadId>>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            owner: MutexInt::new(true, None),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        assert_may_block("Mutex::lock");
+        let me = sched::current();
+        assert_ne!(*self.owner.lock(), Some(me), "mutex already held by this thread");
+        self.waiters.wait_until(|| self.acquire(me));
+        MutexGuard::new(self)
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        assert_may_block("Mutex::try_lock");
+        if self.acquire(sched::current()) {
+            Some(MutexGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    fn acquire(&self, me: ThreadId) -> bool {
+        let mut owner = self.owner.lock();
+        if owner.is_some() {
+            return false;
+        }
+        *owner = Some(me);
+        true
+    }
+
+    /// The thread holding it, if any.
+    pub fn owner(&self) -> Option<ThreadId> {
+        *self.owner.lock()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+/// Unlocks the mutex when dropped. It stays on the thread that locked it.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<'a, T> MutexGuard<'a, T> {
+    fn new(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        MutexGuard {
+            mutex,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// The mutex it holds, for `Condvar` to lock it again.
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        {
+            let mut owner = self.mutex.owner.lock();
+            debug_assert_eq!(*owner, Some(sched::current()), "mutex unlocked by another thread");
+            *owner = None;
+        }
+        self.mutex.waiters.wake_one();
+    }
+}
+
+#[test_case]
+fn mutex_excludes_and_tracks_its_owner() {
+    use alloc::vec::Vec;
+    static COUNTER: Mutex<u64> = Mutex::new(0);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            sched::spawn("counter", || {
+                for _ in 0..100 {
+                    let mut counter = COUNTER.lock();
+                    let value = *counter;
+                    // give the others a chance to get in between
+                    sched::yield_now();
+                    *counter = value + 1;
+                }
+                0
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join();
+    }
+    let counter = COUNTER.lock();
+    assert_eq!(*counter, 400);
+    assert_eq!(COUNTER.owner(), Some(sched::current()));
+    drop(counter);
+    assert_eq!(COUNTER.owner(), None);
+}
@@ -0,0 +1,230 @@
+use super::{assert_may_block, WaitQueue};
+use crate::kernel::sched::{self, ThreadId};
+use crate::util::mutex_int::MutexInt;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+struct State {
+    readers: usize,
+    writer: Option<ThreadId>,
+    // new readers wait behind them, so a stream of readers cannot starve
+    // a writer
+    writers_waiting: usize,
+}
+
+/// A sleeping lock for many readers or one writer. Writers go first.
+pub struct RwLock<T> {
+    state: MutexInt<State>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> RwLock<T> {
+        RwLock {
+            state: MutexInt::new(
+                true,
+                State {
+                    readers: 0,
+                    writer: None,
+                    writers_waiting: 0,
+                },
+            ),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        assert_may_block("RwLock::read");
+        self.check_not_writer();
+        self.waiters.wait_until(|| self.acquire_read());
+        RwLockReadGuard::new(self)
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        assert_may_block("RwLock::try_read");
+        if self.acquire_read() {
+            Some(RwLockReadGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        assert_may_block("RwLock::write");
+        self.check_not_writer();
+        let me = sched::current();
+        if !self.acquire_write(me) {
+            self.state.lock().writers_waiting += 1;
+            self.waiters.wait_until(|| {
+                let acquired = self.acquire_write(me);
+                if acquired {
+                    self.state.lock().writers_waiting -= 1;
+                }
+                acquired
+            });
+        }
+        RwLockWriteGuard::new(self)
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        assert_may_block("RwLock::try_write");
+        if self.acquire_write(sched::current()) {
+            Some(RwLockWriteGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// The thread holding it for writing, if any.
+    pub fn writer(&self) -> Option<ThreadId> {
+        self.state.lock().writer
+    }
+
+    fn check_not_writer(&self) {
+        assert_ne!(
+            self.writer(),
+            Some(sched::current()),
+            "rwlock already held for writing by this thread"
+        );
+    }
+
+    fn acquire_read(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.writer.is_some() || state.writers_waiting > 0 {
+            return false;
+        }
+        state.readers += 1;
+        true
+    }
+
+    fn acquire_write(&self, me: ThreadId) -> bool {
+        let mut state = self.state.lock();
+        if state.writer.is_some() || state.readers > 0 {
+            return false;
+        }
+        state.writer = Some(me);
+        true
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<'a, T> RwLockReadGuard<'a, T> {
+    fn new(lock: &'a RwLock<T>) -> RwLockReadGuard<'a, T> {
+        RwLockReadGuard {
+            lock,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.lock.state.lock();
+            state.readers -= 1;
+            state.readers == 0
+        };
+        if last {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<'a, T> RwLockWriteGuard<'a, T> {
+    fn new(lock: &'a RwLock<T>) -> RwLockWriteGuard<'a, T> {
+        RwLockWriteGuard {
+            lock,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        {
+            let mut state = self.lock.state.lock();
+            debug_assert_eq!(
+                state.writer,
+                Some(sched::current()),
+                "rwlock unlocked by another thread"
+            );
+            state.writer = None;
+        }
+        // all the readers may go, or the next writer
+        self.lock.waiters.wake_all();
+    }
+}
+
+#[test_case]
+fn rwlock_shares_reads_and_excludes_writes() {
+    let lock = RwLock::new(1);
+    {
+        let first = lock.read();
+        let second = lock.try_read().expect("readers exclude each other");
+        assert_eq!(*first + *second, 2);
+        assert!(lock.try_write().is_none());
+    }
+    {
+        let mut writer = lock.write();
+        *writer = 2;
+        assert_eq!(lock.writer(), Some(sched::current()));
+        assert!(lock.try_write().is_none());
+    }
+    assert_eq!(lock.writer(), None);
+    assert_eq!(*lock.read(), 2);
+}
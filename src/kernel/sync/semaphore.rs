@@ -0,0 +1,63 @@
+use super::{assert_may_block, WaitQueue};
+use crate::util::mutex_int::MutexInt;
+use core::time::Duration;
+
+/// A counting semaphore: `acquire` sleeps until a permit is free.
+pub struct Semaphore {
+    permits: MutexInt<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: MutexInt::new(true, permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        assert_may_block("Semaphore::acquire");
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Like `acquire`, but gives up after `timeout`. Returns whether it got
+    /// a permit.
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        assert_may_block("Semaphore::acquire_timeout");
+        self.waiters.wait_until_timeout(|| self.try_acquire(), timeout)
+    }
+
+    /// Takes a permit if one is free. Works from interrupt context.
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.lock();
+        if *permits == 0 {
+            return false;
+        }
+        *permits -= 1;
+        true
+    }
+
+    /// Gives a permit back. Works from interrupt context, so a handler can
+    /// hand work to a thread this way.
+    pub fn release(&self) {
+        *self.permits.lock() += 1;
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        *self.permits.lock()
+    }
+}
+
+#[test_case]
+fn semaphore_counts_permits() {
+    let semaphore = Semaphore::new(2);
+    semaphore.acquire();
+    assert!(semaphore.try_acquire());
+    assert!(!semaphore.try_acquire());
+    assert!(!semaphore.acquire_timeout(Duration::from_millis(2)));
+    semaphore.release();
+    assert_eq!(semaphore.available(), 1);
+    assert!(semaphore.acquire_timeout(Duration::from_millis(2)));
+}
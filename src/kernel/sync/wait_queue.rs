@@ -0,0 +1,207 @@
+use crate::kernel::sched::{self, ThreadId};
+use crate::kernel::time::{Instant, Timer};
+use crate::util::mutex_int::MutexInt;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+/// Threads waiting for some condition, woken up by whoever changes it.
+///
+/// Waiters check the condition themselves after every wakeup, so a wakeup
+/// is only a hint: waking too many is harmless, and a condition made true
+/// before `wake_*` is never missed.
+pub struct WaitQueue {
+    // in the order they started waiting
+    waiters: MutexInt<Vec<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: MutexInt::new(true, Vec::new()),
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns true.
+    pub fn wait_until<F>(&self, condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        self.wait(condition, None);
+    }
+
+    /// Like `wait_until`, but gives up after `timeout`. Returns whether
+    /// `condition` came true.
+    pub fn wait_until_timeout<F>(&self, condition: F, timeout: Duration) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        self.wait(condition, Some(Instant::now() + timeout))
+    }
+
+    fn wait<F>(&self, mut condition: F, deadline: Option<Instant>) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        assert!(
+            !crate::kernel::is_interrupt_context(),
+            "waiting in interrupt context ({})",
+            crate::kernel::interrupt_depth()
+        );
+        if condition() {
+            return true;
+        }
+        let me = sched::current();
+        // wakes us up when the time is up, cancelled when dropped
+        let _timer = deadline.map(|deadline| {
+            let timer = Timer::new(move || sched::unpark(me));
+            timer.start_at(deadline);
+            timer
+        });
+        loop {
+            self.waiters.lock().push(me);
+            // a wakeup from here on unparks us, so `park` does not miss it
+            if condition() {
+                self.remove(me);
+                return true;
+            }
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                if self.remove(me) {
+                    return false;
+                }
+                // a waker took us off the queue since the check: take what
+                // it woke us for, or hand the wakeup on to somebody else
+                if condition() {
+                    return true;
+                }
+                self.wake_one();
+                return false;
+            }
+            sched::park();
+            self.remove(me);
+            if condition() {
+                return true;
+            }
+        }
+    }
+
+    /// Takes `id` off the queue. Returns false if a waker already did.
+    fn remove(&self, id: ThreadId) -> bool {
+        let mut waiters = self.waiters.lock();
+        match waiters.iter().position(|&waiter| waiter == id) {
+            Some(index) => {
+                waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes the longest waiting thread. Returns whether there was one.
+    /// Works from interrupt context.
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        if waiters.is_empty() {
+            return false;
+        }
+        sched::unpark(waiters.remove(0));
+        true
+    }
+
+    /// Wakes every waiting thread and returns how many there were. Works
+    /// from interrupt context.
+    pub fn wake_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        for &waiter in waiters.iter() {
+            sched::unpark(waiter);
+        }
+        let woken = waiters.len();
+        // keep the memory, freeing it is not allowed in interrupt context
+        waiters.clear();
+        woken
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> WaitQueue {
+        WaitQueue::new()
+    }
+}
+
+#[cfg(test)]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[test_case]
+fn wait_queue_wakes_waiters() {
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static READY: AtomicBool = AtomicBool::new(false);
+    let waiters: Vec<_> = (0..3)
+        .map(|_| {
+            sched::spawn("waiter", || {
+                QUEUE.wait_until(|| READY.load(Ordering::SeqCst));
+                7
+            })
+        })
+        .collect();
+    // nothing to wake them for yet
+    QUEUE.wake_all();
+    sched::yield_now();
+    READY.store(true, Ordering::SeqCst);
+    QUEUE.wake_all();
+    for waiter in waiters {
+        assert_eq!(waiter.join(), 7);
+    }
+    assert!(!QUEUE.wake_one());
+}
+
+#[test_case]
+fn wait_queue_times_out() {
+    let queue = WaitQueue::new();
+    let start = Instant::now();
+    assert!(!queue.wait_until_timeout(|| false, Duration::from_millis(5)));
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    assert!(queue.wait_until_timeout(|| true, Duration::from_millis(5)));
+}
+
+#[test_case]
+fn wait_queue_timeout_passes_wakeups_on() {
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static PERMITS: AtomicUsize = AtomicUsize::new(0);
+    fn take() -> bool {
+        PERMITS
+            .compare_exchange(1, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    let other = sched::spawn("waiter", || {
+        QUEUE.wait_until(take);
+        7
+    });
+    while QUEUE.waiters.lock().is_empty() {
+        sched::yield_now();
+    }
+
+    // times out right away, with a permit showing up between the last check
+    // and giving up
+    let me = sched::current();
+    let mut checks = 0;
+    let got = QUEUE.wait_until_timeout(
+        || {
+            checks += 1;
+            if checks == 2 {
+                PERMITS.store(1, Ordering::SeqCst);
+                // what `wake_one` does if we are first in line
+                QUEUE.remove(me);
+                sched::unpark(me);
+                return false;
+            }
+            take()
+        },
+        Duration::from_secs(0),
+    );
+    // the permit went to one of the two, not to nobody
+    if got {
+        PERMITS.store(1, Ordering::SeqCst);
+        QUEUE.wake_one();
+    }
+    assert_eq!(other.join(), 7);
+}
@@ -0,0 +1,370 @@
+use super::gdt;
+use super::memory::user_virtual_range;
+use super::sched;
+use super::smp;
+use alloc::sync::Arc;
+use core::time::Duration;
+use x86_64::{
+    instructions::interrupts,
+    registers::model_specific::{Efer, EferFlags, Msr},
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+
+// TF | IF | DF, cleared on entry
+const SYSCALL_RFLAGS_MASK: u64 = 0x100 | 0x200 | 0x400;
+
+pub mod nr {
+    pub const WRITE: u64 = 0;
+    pub const EXIT: u64 = 1;
+    /// Takes nanoseconds.
+    pub const SLEEP: u64 = 2;
+    pub const YIELD: u64 = 3;
+    pub const MMAP: u64 = 4;
+    pub const GETPID: u64 = 5;
+    pub const FORK: u64 = 6;
+}
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// Errors handed back to user mode as negative errno values.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(i64)]
+pub enum SyscallError {
+    BadFd = 9,
+    NoMemory = 12,
+    Fault = 14,
+    Invalid = 22,
+    NoSys = 38,
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+/// User registers saved by the entry stub, lowest address first.
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    /// Syscall number on entry.
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// User rflags, saved by `syscall`.
+    pub r11: u64,
+    /// User rip, saved by `syscall`.
+    pub rcx: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    pub fn arg(&self, idx: usize) -> u64 {
+        match idx {
+            0 => self.rdi,
+            1 => self.rsi,
+            2 => self.rdx,
+            3 => self.r10,
+            4 => self.r8,
+            5 => self.r9,
+            _ => panic!("syscalls take at most 6 arguments"),
+        }
+    }
+}
+
+type SyscallHandler = fn(&mut SyscallFrame) -> SyscallResult;
+
+static SYSCALL_TABLE: [SyscallHandler; 7] = [
+    sys_write,  // nr::WRITE
+    sys_exit,   // nr::EXIT
+    sys_sleep,  // nr::SLEEP
+    sys_yield,  // nr::YIELD
+    sys_mmap,   // nr::MMAP
+    sys_getpid, // nr::GETPID
+    sys_fork,   // nr::FORK
+];
+
+// `syscall` does not switch stacks, the entry stub swaps in the kernel GS base
+// and picks the kernel stack of the current thread from the cpu's
+// `smp::Cpu`: the kernel stack at offset 8, room for the user stack at 16
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global __ngos_syscall_entry
+__ngos_syscall_entry:
+    swapgs
+    mov qword ptr gs:[16], rsp
+    mov rsp, qword ptr gs:[8]
+    push qword ptr gs:[16]
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    call __ngos_syscall_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    add rsp, 8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+
+.global __ngos_syscall_return
+__ngos_syscall_return:
+    mov rsp, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+.att_syntax prefix
+"#
+);
+
+extern "C" {
+    fn __ngos_syscall_entry();
+    fn __ngos_syscall_return(frame: *const SyscallFrame) -> !;
+}
+
+/// Leaves for user mode with every register taken from `frame`, as if
+/// returning from the system call that saved it.
+fn return_to_user(frame: &SyscallFrame) -> ! {
+    interrupts::disable();
+    unsafe { __ngos_syscall_return(frame) }
+}
+
+#[no_mangle]
+extern "C" fn __ngos_syscall_dispatch(frame: &mut SyscallFrame) -> u64 {
+    interrupts::enable();
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => Err(SyscallError::NoSys),
+    };
+    interrupts::disable();
+    match result {
+        Ok(value) => value,
+        Err(err) => (-(err as i64)) as u64,
+    }
+}
+
+/// Sets the stack `syscall` lands on for the running cpu, called on every
+/// context switch.
+pub fn set_kernel_stack(top: VirtAddr) {
+    smp::current().set_syscall_stack(top);
+}
+
+/// Checks that `[ptr, ptr + len)` lies in the user half and maps it in, so
+/// the kernel can touch it without faulting or leaking kernel memory.
+fn user_range(
+    ptr: u64,
+    len: u64,
+    writable: bool,
+) -> Result<core::ops::Range<u64>, SyscallError> {
+    let end = ptr.checked_add(len).ok_or(SyscallError::Fault)?;
+    let user = user_virtual_range();
+    if ptr < user.start || end > user.end {
+        return Err(SyscallError::Fault);
+    }
+    let space = sched::current_addr_space().ok_or(SyscallError::Fault)?;
+    space
+        .fault_in_user_range(ptr..end, writable)
+        .map_err(|_| SyscallError::Fault)?;
+    Ok(ptr..end)
+}
+
+fn user_slice(ptr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    let range = user_range(ptr, len, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(range.start as *const u8, len as usize) })
+}
+
+fn sys_write(frame: &mut SyscallFrame) -> SyscallResult {
+    let (fd, buf, len) = (frame.arg(0), frame.arg(1), frame.arg(2));
+    if fd != 1 && fd != 2 {
+        return Err(SyscallError::BadFd);
+    }
+    let bytes = user_slice(buf, len)?;
+    let text = core::str::from_utf8(bytes).map_err(|_| SyscallError::Invalid)?;
+    print!("{}", text);
+    crate::serial_print!("{}", text);
+    Ok(len)
+}
+
+fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult {
+    sched::exit(frame.arg(0) as i32)
+}
+
+fn sys_sleep(frame: &mut SyscallFrame) -> SyscallResult {
+    super::time::sleep(Duration::from_nanos(frame.arg(0)));
+    Ok(0)
+}
+
+fn sys_yield(_frame: &mut SyscallFrame) -> SyscallResult {
+    sched::yield_now();
+    Ok(0)
+}
+
+fn sys_mmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let (len, prot) = (frame.arg(1), frame.arg(2));
+    if len == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::Invalid);
+    }
+    let pages = num::integer::div_ceil(len, 4096);
+    if pages > (user_virtual_range().end - user_virtual_range().start) / 4096 {
+        return Err(SyscallError::NoMemory);
+    }
+
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let space = sched::current_addr_space().ok_or(SyscallError::NoMemory)?;
+    let range = space
+        .alloc_user_pages(pages, flags)
+        .map_err(|_| SyscallError::NoMemory)?;
+    Ok(range.start.start_address().as_u64())
+}
+
+fn sys_getpid(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(sched::current().as_u64())
+}
+
+/// Starts a copy of the calling thread in a copy-on-write duplicate of its
+/// address space. Returns the new thread id to the parent and 0 to the
+/// child.
+fn sys_fork(frame: &mut SyscallFrame) -> SyscallResult {
+    let space = sched::current_addr_space().ok_or(SyscallError::Invalid)?;
+    let mut child_frame = frame.clone();
+    child_frame.rax = 0;
+    let child = sched::spawn_in("fork", Arc::new(space.fork()), move || {
+        return_to_user(&child_frame)
+    });
+    Ok(child.id().as_u64())
+}
+
+fn enable() {
+    let selectors = gdt::selectors();
+    let kernel_code = selectors.code_selector.0 as u64;
+    // sysret loads ss from base + 8 and cs from base + 16
+    let sysret_base = (selectors.user_data_selector.0 as u64 - 8) | 3;
+    assert_eq!(
+        sysret_base + 16,
+        selectors.user_code_selector.0 as u64,
+        "bad gdt layout for sysret"
+    );
+
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+        Msr::new(IA32_STAR).write((sysret_base << 48) | (kernel_code << 32));
+        Msr::new(IA32_LSTAR).write(__ngos_syscall_entry as u64);
+        Msr::new(IA32_FMASK).write(SYSCALL_RFLAGS_MASK);
+    }
+}
+
+pub fn init() {
+    crate::call_stack!();
+    enable();
+}
+
+/// Enables `syscall` on an application processor, the MSRs are per cpu.
+pub fn init_ap() {
+    enable();
+}
+
+#[test_case]
+fn syscall_getpid_and_exit() {
+    let code = [
+        0xb8, 0x05, 0x00, 0x00, 0x00, // mov eax, GETPID
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+    ];
+    let code = super::user::run_user_code("getpid", &code);
+    assert!(code > 1, "user thread reported pid {}", code);
+}
+
+#[test_case]
+fn syscall_rejects_kernel_pointer() {
+    let mut code = [
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, WRITE
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+        0x48, 0xbe, 0, 0, 0, 0, 0, 0, 0, 0, // mov rsi, imm64
+        0xba, 0x04, 0x00, 0x00, 0x00, // mov edx, 4
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+    ];
+    let target = super::memory::kernel_virtual_range().start;
+    code[12..20].copy_from_slice(&target.to_le_bytes());
+    assert_eq!(
+        super::user::run_user_code("bad-write", &code),
+        -(SyscallError::Fault as i32)
+    );
+}
+
+#[test_case]
+fn syscall_fork_returns_child_id() {
+    let code = [
+        0xb8, 0x06, 0x00, 0x00, 0x00, // mov eax, FORK
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+    ];
+    // the child exits with 0 on its own, the parent with the child's id
+    let code = super::user::run_user_code("fork", &code);
+    assert!(code > 1, "fork returned {}", code);
+}
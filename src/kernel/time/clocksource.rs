@@ -0,0 +1,211 @@
+use crate::util::mutex_int::MutexInt;
+use crate::util::seqlock::SeqLock;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A free running counter the monotonic clock is read from.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    /// How good it is, the best one registered is used: 300 for a counter
+    /// that is cheap to read and never stops, down to 100 for one only good
+    /// while nothing better is around.
+    fn rating(&self) -> u32;
+    /// Counts per second.
+    fn frequency(&self) -> u64;
+    /// The counter, it wraps around at `mask`.
+    fn read(&self) -> u64;
+    fn mask(&self) -> u64;
+}
+
+/// A device raising the periodic tick on `TIMER_IRQ`.
+pub trait ClockEvent: Sync {
+    fn name(&self) -> &'static str;
+    /// Same scale as for `ClockSource`.
+    fn rating(&self) -> u32;
+    /// Starts raising the tick `hz` times a second.
+    fn start_periodic(&self, hz: u32);
+    fn stop(&self);
+}
+
+/// Turns readings of a clock source into nanoseconds. Folding the count in
+/// at least once per half a wrap of the counter keeps it from losing time,
+/// the tick does that.
+#[derive(Copy, Clone)]
+struct Clock {
+    source: Option<&'static dyn ClockSource>,
+    /// Nanoseconds per count, 32.32 fixed point.
+    mult: u64,
+    last: u64,
+    /// Nanoseconds up to `last`, 32.32 fixed point so no fractions are lost.
+    nanos: u128,
+}
+
+impl Clock {
+    const fn new() -> Clock {
+        Clock {
+            source: None,
+            mult: 0,
+            last: 0,
+            nanos: 0,
+        }
+    }
+
+    /// Counts since `last`, and the counter value they go up to.
+    fn delta(&self) -> (u64, u64) {
+        match self.source {
+            Some(source) => {
+                let now = source.read();
+                let delta = now.wrapping_sub(self.last) & source.mask();
+                // a counter seemingly gone around more than halfway was read
+                // a bit behind, on another cpu; time does not go back
+                if delta <= source.mask() / 2 {
+                    (delta, now)
+                } else {
+                    (0, self.last)
+                }
+            }
+            None => (0, self.last),
+        }
+    }
+
+    fn read(&self) -> u64 {
+        let (delta, _) = self.delta();
+        ((self.nanos + delta as u128 * self.mult as u128) >> 32) as u64
+    }
+
+    /// Folds the counts so far into `nanos`.
+    fn update(&mut self) {
+        let (delta, now) = self.delta();
+        self.nanos += delta as u128 * self.mult as u128;
+        self.last = now;
+    }
+
+    /// Carries on counting from `source`, where the previous one left off.
+    fn switch_to(&mut self, source: &'static dyn ClockSource) {
+        self.update();
+        self.mult = ((1_000_000_000u128 << 32) / source.frequency() as u128) as u64;
+        self.last = source.read();
+        self.source = Some(source);
+    }
+}
+
+/// Serializes the writers of `PUBLISHED`, which readers copy without a lock.
+static CLOCK: MutexInt<Clock> = MutexInt::new(true, Clock::new());
+static PUBLISHED: SeqLock<Clock> = SeqLock::new(Clock::new());
+/// Highest value handed out, so readers on cpus whose counters lag a bit
+/// never see time go back.
+static LATEST: AtomicU64 = AtomicU64::new(0);
+static TICK_DEVICE: MutexInt<Option<&'static dyn ClockEvent>> = MutexInt::new(true, None);
+
+/// Nanoseconds since the first clock source was registered.
+pub fn nanos() -> u64 {
+    let now = PUBLISHED.read().read();
+    let mut latest = LATEST.load(Ordering::Relaxed);
+    while now > latest {
+        match LATEST.compare_exchange_weak(latest, now, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return now,
+            Err(seen) => latest = seen,
+        }
+    }
+    latest
+}
+
+/// Folds the counter into the clock, the tick calls it so that the counter
+/// never wraps unnoticed.
+pub fn update() {
+    let mut clock = CLOCK.lock();
+    clock.update();
+    PUBLISHED.write(*clock);
+}
+
+/// Switches to `source` if it is better than the one in use.
+pub fn register_clocksource(source: &'static dyn ClockSource) {
+    {
+        let mut clock = CLOCK.lock();
+        if clock.source.map_or(false, |current| current.rating() >= source.rating()) {
+            return;
+        }
+        clock.switch_to(source);
+        PUBLISHED.write(*clock);
+    }
+    // not under the lock, logging may read the clock
+    log::info!(
+        "clocksource: {} at {} kHz, rating {}",
+        source.name(),
+        source.frequency() / 1000,
+        source.rating()
+    );
+}
+
+/// Moves the tick to `event`, at `hz`, if it is better than the device
+/// raising it now.
+pub fn register_clockevent(event: &'static dyn ClockEvent, hz: u32) {
+    {
+        let mut tick = TICK_DEVICE.lock();
+        if tick.map_or(false, |current| current.rating() >= event.rating()) {
+            return;
+        }
+        if let Some(current) = tick.replace(event) {
+            current.stop();
+        }
+        event.start_periodic(hz);
+    }
+    log::info!("tick: {} at {} Hz, rating {}", event.name(), hz, event.rating());
+}
+
+#[cfg(test)]
+struct TestSource {
+    counter: AtomicU64,
+}
+
+#[cfg(test)]
+impl ClockSource for TestSource {
+    fn name(&self) -> &'static str {
+        "test"
+    }
+
+    fn rating(&self) -> u32 {
+        0
+    }
+
+    fn frequency(&self) -> u64 {
+        1_000_000
+    }
+
+    fn read(&self) -> u64 {
+        self.counter.load(Ordering::SeqCst)
+    }
+
+    fn mask(&self) -> u64 {
+        0xffff
+    }
+}
+
+#[test_case]
+fn clock_survives_wraps_and_stale_reads() {
+    static SOURCE: TestSource = TestSource {
+        counter: AtomicU64::new(0xff00),
+    };
+    let mut clock = Clock::new();
+    clock.switch_to(&SOURCE);
+    assert_eq!(clock.read(), 0);
+    // wraps past 0xffff, 0x200 counts of a microsecond each
+    SOURCE.counter.store(0x100, Ordering::SeqCst);
+    assert_eq!(clock.read(), 0x200 * 1000);
+    clock.update();
+    // read behind on another cpu
+    SOURCE.counter.store(0xf0, Ordering::SeqCst);
+    assert_eq!(clock.read(), 0x200 * 1000);
+    clock.update();
+    SOURCE.counter.store(0x101, Ordering::SeqCst);
+    assert_eq!(clock.read(), 0x201 * 1000);
+}
+
+#[test_case]
+fn clock_reads_do_not_go_back() {
+    let mut last = nanos();
+    for _ in 0..1000 {
+        let now = nanos();
+        assert!(now >= last);
+        last = now;
+    }
+}
@@ -0,0 +1,128 @@
+use core::fmt;
+use core::time::Duration;
+
+const SECS_PER_DAY: u64 = 86_400;
+// days from 0000-03-01 to 1970-01-01, counting years from March so the leap
+// day comes last
+const EPOCH_DAYS: u64 = 719_468;
+const DAYS_PER_ERA: u64 = 146_097;
+
+/// A UTC date and time, from 1970 on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DateTime {
+    pub year: u32,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanos: u32,
+}
+
+fn days_in_month(year: u32, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// Whether every field is in range, and the date not before 1970.
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+            && self.nanos < 1_000_000_000
+    }
+
+    /// Time since the UNIX epoch. Only meaningful for valid dates.
+    pub fn to_unix(&self) -> Duration {
+        let year = self.year as u64 - (self.month <= 2) as u64;
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let month_from_march = (self.month as u64 + 9) % 12;
+        let day_of_year = (153 * month_from_march + 2) / 5 + self.day as u64 - 1;
+        let day_of_era =
+            year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * DAYS_PER_ERA + day_of_era - EPOCH_DAYS;
+        let secs = days * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64;
+        Duration::new(secs, self.nanos)
+    }
+
+    pub fn from_unix(since_epoch: Duration) -> DateTime {
+        let secs = since_epoch.as_secs();
+        let days = secs / SECS_PER_DAY + EPOCH_DAYS;
+        let era = days / DAYS_PER_ERA;
+        let day_of_era = days % DAYS_PER_ERA;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let month = if month_from_march < 10 {
+            month_from_march + 3
+        } else {
+            month_from_march - 9
+        };
+        let secs_of_day = secs % SECS_PER_DAY;
+        DateTime {
+            year: (era * 400 + year_of_era + (month <= 2) as u64) as u32,
+            month: month as u8,
+            day: (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            nanos: since_epoch.subsec_nanos(),
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.nanos / 1_000_000
+        )
+    }
+}
+
+#[test_case]
+fn dates_convert_to_and_from_unix_time() {
+    let leap_day = DateTime {
+        year: 2020,
+        month: 2,
+        day: 29,
+        hour: 12,
+        minute: 34,
+        second: 56,
+        nanos: 789_000_000,
+    };
+    assert_eq!(leap_day.to_unix(), Duration::new(1_582_979_696, 789_000_000));
+    assert_eq!(DateTime::from_unix(leap_day.to_unix()), leap_day);
+    assert_eq!(alloc::format!("{}", leap_day), "2020-02-29 12:34:56.789");
+
+    let epoch = DateTime::from_unix(Duration::from_secs(0));
+    assert_eq!((epoch.year, epoch.month, epoch.day, epoch.hour), (1970, 1, 1, 0));
+    let march = DateTime::from_unix(Duration::from_secs(951_868_800));
+    assert_eq!((march.year, march.month, march.day), (2000, 3, 1));
+    let end_of_year = DateTime::from_unix(Duration::from_secs(1_609_459_199));
+    assert_eq!((end_of_year.year, end_of_year.month, end_of_year.day), (2020, 12, 31));
+    assert_eq!((end_of_year.hour, end_of_year.minute, end_of_year.second), (23, 59, 59));
+}
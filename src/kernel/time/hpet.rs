@@ -0,0 +1,139 @@
+use super::clocksource::{register_clockevent, register_clocksource, ClockEvent, ClockSource};
+use super::TICK_HZ;
+use crate::kernel::{acpi, memory::map_mmio};
+use crate::util::init_cell::InitCell;
+use x86_64::{PhysAddr, VirtAddr};
+
+const CAPABILITIES: usize = 0x000;
+const CONFIG: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const TIMER_0_CONFIG: usize = 0x100;
+const TIMER_0_COMPARATOR: usize = 0x108;
+
+const CAPABILITY_64BIT: u64 = 1 << 13;
+const CAPABILITY_LEGACY: u64 = 1 << 15;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+// timer 0 takes over IRQ 0 from the PIT, timer 1 IRQ 8 from the RTC
+const CONFIG_LEGACY: u64 = 1 << 1;
+
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+// the next comparator write sets the value, the one after it the period
+const TIMER_VALUE_SET: u64 = 1 << 6;
+
+/// The spec caps the counter period at 100ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// The first HPET block: its main counter as a clock source, and timer 0 in
+/// legacy replacement mode for the tick.
+struct Hpet {
+    base: VirtAddr,
+    frequency: u64,
+    counter_64bit: bool,
+}
+
+impl Hpet {
+    fn read_reg(&self, reg: usize) -> u64 {
+        unsafe { core::ptr::read_volatile((self.base.as_u64() as usize + reg) as *const u64) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u64) {
+        unsafe { core::ptr::write_volatile((self.base.as_u64() as usize + reg) as *mut u64, value) }
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    // steady, but every read is a trip to the chipset
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn read(&self) -> u64 {
+        self.read_reg(MAIN_COUNTER) & self.mask()
+    }
+
+    fn mask(&self) -> u64 {
+        if self.counter_64bit {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        }
+    }
+}
+
+impl ClockEvent for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        150
+    }
+
+    fn start_periodic(&self, hz: u32) {
+        let period = (self.frequency / hz as u64).max(1);
+        let config = self.read_reg(TIMER_0_CONFIG);
+        self.write_reg(
+            TIMER_0_CONFIG,
+            config | TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET,
+        );
+        // first expiry, then the period; the counter keeps running for the
+        // clock source
+        self.write_reg(TIMER_0_COMPARATOR, self.read() + period);
+        self.write_reg(TIMER_0_COMPARATOR, period);
+        self.write_reg(CONFIG, self.read_reg(CONFIG) | CONFIG_LEGACY);
+    }
+
+    fn stop(&self) {
+        self.write_reg(CONFIG, self.read_reg(CONFIG) & !CONFIG_LEGACY);
+        self.write_reg(TIMER_0_CONFIG, self.read_reg(TIMER_0_CONFIG) & !TIMER_INT_ENABLE);
+    }
+}
+
+static HPET: InitCell<Hpet> = InitCell::new();
+
+/// Needs the heap for mapping the registers, and the ACPI tables to find
+/// them.
+pub fn init() {
+    let table = match acpi::hpet() {
+        Some(table) => table,
+        None => {
+            log::info!("hpet: none");
+            return;
+        }
+    };
+    let base = map_mmio(PhysAddr::new(table.base.address), 0x400);
+    let capabilities =
+        unsafe { core::ptr::read_volatile((base.as_u64() as usize + CAPABILITIES) as *const u64) };
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        log::warn!("hpet: bad counter period {} fs, ignoring it", period_fs);
+        return;
+    }
+    HPET.init(Hpet {
+        base,
+        frequency: 1_000_000_000_000_000 / period_fs,
+        counter_64bit: capabilities & CAPABILITY_64BIT != 0,
+    });
+    let hpet = HPET.get();
+    hpet.write_reg(CONFIG, hpet.read_reg(CONFIG) | CONFIG_ENABLE);
+    register_clocksource(hpet);
+
+    let tick_capable = capabilities & CAPABILITY_LEGACY != 0
+        && hpet.read_reg(TIMER_0_CONFIG) & TIMER_PERIODIC_CAPABLE != 0;
+    if tick_capable {
+        register_clockevent(hpet, TICK_HZ);
+    } else {
+        log::info!("hpet: timer 0 cannot raise the tick");
+    }
+}
@@ -0,0 +1,177 @@
+use super::irq::{register_irq, IrqReturn};
+use super::softirq::{raise_softirq, SoftIrq};
+use core::ops::{Add, Sub};
+use core::time::Duration;
+use crate::util::init_cell::InitCell;
+
+mod clocksource;
+mod date;
+mod hpet;
+mod pit;
+mod rtc;
+mod timer;
+mod tsc;
+
+pub use date::DateTime;
+pub use pit::{pit_delay_us, CALIBRATION_US};
+pub use timer::{needs_tick, subscribe_timer, Timer};
+
+/// Raised by whichever clock event device is best, the PIT to begin with.
+const TIMER_IRQ: u8 = 0;
+
+/// Rate of the periodic tick, which drives the scheduler and keeps the clock
+/// from missing wraps of its counter.
+pub const TICK_HZ: u32 = 100;
+
+/// Time since the UNIX epoch at `Instant` zero, from the RTC.
+static BOOT_TIME: InitCell<Duration> = InitCell::new();
+
+/// A point on the monotonic clock, nanoseconds since the first clock source
+/// came up at boot.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(clocksource::nanos())
+    }
+
+    pub fn from_nanos(nanos: u64) -> Instant {
+        Instant(nanos)
+    }
+
+    pub fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(rhs.as_nanos() as u64))
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_sub(rhs.as_nanos() as u64))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Blocks the current thread for `duration`, leaving the cpu to others.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    let me = super::sched::current();
+    let timer = Timer::new(move || super::sched::unpark(me));
+    timer.start_at(deadline);
+    // `park` may return early
+    while Instant::now() < deadline {
+        super::sched::park();
+    }
+}
+
+/// Time since the UNIX epoch, None until the RTC has been read. It goes on
+/// from the RTC on the monotonic clock, so it is only as good as both.
+pub fn wall_clock() -> Option<Duration> {
+    BOOT_TIME
+        .try_get()
+        .map(|&boot_time| boot_time + Instant::now().duration_since(Instant(0)))
+}
+
+fn init_wall_clock() {
+    let fadt = super::acpi::fadt();
+    if !fadt.map_or(true, |fadt| fadt.has_cmos_rtc) {
+        log::warn!("rtc: none, no wall clock");
+        return;
+    }
+    let now = match rtc::read(fadt.and_then(|fadt| fadt.century_register)) {
+        Some(now) => now,
+        None => {
+            log::warn!("rtc: holds no valid date, no wall clock");
+            return;
+        }
+    };
+    let since_boot = Instant::now().duration_since(Instant(0));
+    BOOT_TIME.init(now.to_unix().checked_sub(since_boot).unwrap_or_default());
+    log::info!("rtc: {} UTC", now);
+}
+
+fn timer_irq() -> IrqReturn {
+    // keeps the clock from missing a wrap of its counter
+    clocksource::update();
+    if timer::needs_tick() {
+        raise_softirq(SoftIrq::Timer);
+    }
+    super::sched::tick();
+    super::smp::broadcast_tick();
+    IrqReturn::Handled
+}
+
+pub fn init() {
+    tsc::init();
+    pit::init();
+    register_irq(TIMER_IRQ, timer_irq).expect("timer irq taken");
+}
+
+/// Brings up the HPET, the wall clock and `Timer`s, once there is a heap,
+/// the ACPI tables and the interrupt controller is settled.
+pub fn init_late() {
+    hpet::init();
+    init_wall_clock();
+    timer::init();
+}
+
+#[test_case]
+fn clock_follows_the_pit() {
+    let start = Instant::now();
+    pit_delay_us(2000);
+    let elapsed = start.elapsed();
+    // the pit and the clock source disagree a bit under emulation
+    assert!(elapsed >= Duration::from_micros(1500), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(50), "{:?}", elapsed);
+    assert!(Instant::now() >= start + elapsed);
+}
+
+#[test_case]
+fn wall_clock_runs() {
+    let start = wall_clock().expect("no wall clock");
+    // the build is newer than 2020-09-13
+    assert!(start > Duration::from_secs(1_600_000_000), "{:?}", start);
+    pit_delay_us(1000);
+    assert!(wall_clock().unwrap() > start);
+}
+
+#[test_case]
+fn sleep_leaves_the_cpu_to_others() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static RAN: AtomicBool = AtomicBool::new(false);
+    let start = Instant::now();
+    let other = super::sched::spawn("other", || {
+        RAN.store(true, Ordering::SeqCst);
+        0
+    });
+    sleep(Duration::from_millis(5));
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    assert!(RAN.load(Ordering::SeqCst));
+    other.join();
+}
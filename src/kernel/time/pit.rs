@@ -0,0 +1,79 @@
+use super::clocksource::{register_clockevent, ClockEvent};
+use super::TICK_HZ;
+use x86_64::instructions::port::Port;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// bit 0 gates channel 2, bit 1 feeds it to the speaker, bit 5 reads its output
+const PORT_B: u16 = 0x61;
+
+// channel 0, low byte then high byte; rate generator or interrupt on
+// terminal count
+const CHANNEL_0_PERIODIC: u8 = 0b0011_0100;
+const CHANNEL_0_ONE_SHOT: u8 = 0b0011_0000;
+
+/// How long clocks are counted against the PIT to calibrate them. Longer is
+/// more precise, this keeps the error well under 0.1%.
+pub const CALIBRATION_US: u64 = 10_000;
+
+/// Busy waits for `us` microseconds on PIT channel 2. Needs nothing set up,
+/// so it works before any clock is calibrated, but only one cpu may use it
+/// at a time.
+pub fn pit_delay_us(us: u64) {
+    let mut remaining = us * PIT_FREQUENCY / 1_000_000;
+    while remaining > 0 {
+        let count = core::cmp::min(remaining, 0xffff);
+        unsafe {
+            let mut port_b: Port<u8> = Port::new(PORT_B);
+            let gate_off = port_b.read() & !0b11;
+            port_b.write(gate_off);
+            // channel 2, low byte then high byte, interrupt on terminal count
+            Port::<u8>::new(PIT_COMMAND).write(0b1011_0000);
+            let mut channel: Port<u8> = Port::new(PIT_CHANNEL_2);
+            channel.write(count as u8);
+            channel.write((count >> 8) as u8);
+            port_b.write(gate_off | 1);
+            while port_b.read() & (1 << 5) == 0 {
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+        remaining -= count;
+    }
+}
+
+/// Channel 0, wired to `TIMER_IRQ`. Always there, but slow to program and
+/// too coarse for anything but the tick.
+struct PitTick;
+
+impl ClockEvent for PitTick {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn start_periodic(&self, hz: u32) {
+        let divisor = (PIT_FREQUENCY / hz as u64).max(1).min(0xffff);
+        unsafe {
+            Port::<u8>::new(PIT_COMMAND).write(CHANNEL_0_PERIODIC);
+            let mut channel: Port<u8> = Port::new(PIT_CHANNEL_0);
+            channel.write(divisor as u8);
+            channel.write((divisor >> 8) as u8);
+        }
+    }
+
+    fn stop(&self) {
+        // waits for a count that never comes, so the output stays low
+        unsafe { Port::<u8>::new(PIT_COMMAND).write(CHANNEL_0_ONE_SHOT) };
+    }
+}
+
+static PIT_TICK: PitTick = PitTick;
+
+pub fn init() {
+    register_clockevent(&PIT_TICK, TICK_HZ);
+}
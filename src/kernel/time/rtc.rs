@@ -0,0 +1,149 @@
+use super::date::DateTime;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// set in every address written, so no NMI comes between it and the data
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATING: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOURS_PM: u8 = 0x80;
+
+/// The date and time registers as the chip has them, in BCD or binary as
+/// status register B says.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+fn read_cmos(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn wait_update_done() {
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+fn read_raw(century_register: Option<u8>) -> RawTime {
+    RawTime {
+        second: read_cmos(REG_SECONDS),
+        minute: read_cmos(REG_MINUTES),
+        hour: read_cmos(REG_HOURS),
+        day: read_cmos(REG_DAY),
+        month: read_cmos(REG_MONTH),
+        year: read_cmos(REG_YEAR),
+        century: century_register.map(read_cmos),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// The date and time in the registers, `None` if they do not make one,
+/// like after the battery went flat.
+fn decode(raw: RawTime, status_b: u8) -> Option<DateTime> {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let number = |value: u8| if binary { value } else { from_bcd(value) };
+    let mut hour = number(raw.hour & !HOURS_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 comes before 1, am and pm alike
+        hour %= 12;
+        if raw.hour & HOURS_PM != 0 {
+            hour += 12;
+        }
+    }
+    // without a century register, assume this one
+    let century = raw.century.map_or(20, number) as u32;
+    let time = DateTime {
+        year: century * 100 + number(raw.year) as u32,
+        month: number(raw.month),
+        day: number(raw.day),
+        hour,
+        minute: number(raw.minute),
+        second: number(raw.second),
+        nanos: 0,
+    };
+    if time.is_valid() {
+        Some(time)
+    } else {
+        None
+    }
+}
+
+/// Reads the date and time off the CMOS clock. The registers are read until
+/// two reads agree, so an update of the chip in between is not mixed in.
+/// Returns `None` if the chip holds garbage.
+pub fn read(century_register: Option<u8>) -> Option<DateTime> {
+    interrupts::without_interrupts(|| {
+        let raw = loop {
+            wait_update_done();
+            let first = read_raw(century_register);
+            wait_update_done();
+            if read_raw(century_register) == first {
+                break first;
+            }
+        };
+        decode(raw, read_cmos(REG_STATUS_B))
+    })
+}
+
+#[test_case]
+fn rtc_registers_decode() {
+    let bcd = RawTime {
+        second: 0x56,
+        minute: 0x34,
+        hour: HOURS_PM | 0x12,
+        day: 0x29,
+        month: 0x02,
+        year: 0x20,
+        century: Some(0x20),
+    };
+    // 12 pm is noon
+    let noon = decode(bcd, 0).unwrap();
+    assert_eq!((noon.year, noon.month, noon.day), (2020, 2, 29));
+    assert_eq!((noon.hour, noon.minute, noon.second), (12, 34, 56));
+    assert_eq!(decode(RawTime { hour: 0x12, ..bcd }, 0).unwrap().hour, 0);
+
+    let binary = RawTime {
+        second: 56,
+        minute: 34,
+        hour: 23,
+        day: 31,
+        month: 12,
+        year: 99,
+        century: None,
+    };
+    let late = decode(binary, STATUS_B_BINARY | STATUS_B_24_HOUR).unwrap();
+    assert_eq!((late.year, late.month, late.day, late.hour), (2099, 12, 31, 23));
+
+    // a flat battery leaves zeroes or garbage behind
+    assert_eq!(decode(RawTime { day: 0, ..bcd }, 0), None);
+    assert_eq!(decode(RawTime { month: 0x13, ..bcd }, 0), None);
+    assert_eq!(decode(RawTime { minute: 0xff, ..bcd }, 0), None);
+    assert_eq!(decode(RawTime { century: Some(0x19), ..bcd }, 0), None);
+    assert_eq!(decode(RawTime { day: 0x29, year: 0x21, ..bcd }, 0), None);
+}
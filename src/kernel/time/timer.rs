@@ -0,0 +1,491 @@
+use super::{pit_delay_us, Instant, CALIBRATION_US};
+use crate::kernel::irqchip;
+use crate::kernel::smp;
+use crate::kernel::softirq::{open_softirq, SoftIrq};
+use crate::kernel::MAX_CPUS;
+use crate::util::init_cell::InitCell;
+use crate::util::mutex_int::MutexInt;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Periodic timers fire at most this often, so a cpu cannot be kept in the
+/// timer softirq forever.
+const MIN_PERIOD: Duration = Duration::from_micros(10);
+
+/// Rate the local APIC timers count down at, 0 without them. Then timers
+/// expire on the PIT tick instead of exactly when due.
+static LOCAL_TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Where an armed timer sits, guarded by the `TIMERS` lock.
+struct Placement {
+    cpu: usize,
+    index: usize,
+    period: Option<Duration>,
+}
+
+struct TimerEntry {
+    callback: Box<dyn Fn() + Send + Sync>,
+    placement: UnsafeCell<Option<Placement>>,
+    /// The callback is running, set and cleared around it by the softirq.
+    running: AtomicBool,
+}
+
+// `placement` is only touched with the `TIMERS` lock held
+unsafe impl Send for TimerEntry {}
+unsafe impl Sync for TimerEntry {}
+
+impl TimerEntry {
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn placement(&self) -> &mut Option<Placement> {
+        &mut *self.placement.get()
+    }
+}
+
+struct Slot {
+    deadline: Instant,
+    // breaks ties between equal deadlines in the order they were armed
+    seq: u64,
+    entry: *const TimerEntry,
+}
+
+impl Slot {
+    fn key(&self) -> (Instant, u64) {
+        (self.deadline, self.seq)
+    }
+}
+
+/// The armed timers of one cpu, a binary min-heap on the deadline. Every
+/// entry knows its index, so it can be taken out from the middle.
+struct Queue {
+    cpu: usize,
+    slots: Vec<Slot>,
+}
+
+impl Queue {
+    fn peek(&self) -> Option<&Slot> {
+        self.slots.first()
+    }
+
+    fn push(&mut self, slot: Slot, period: Option<Duration>) {
+        // capacity was reserved when the timer was created, so this does not
+        // allocate in interrupt context
+        debug_assert!(self.slots.len() < self.slots.capacity());
+        let entry = slot.entry;
+        self.slots.push(slot);
+        let index = self.slots.len() - 1;
+        unsafe {
+            *(*entry).placement() = Some(Placement {
+                cpu: self.cpu,
+                index,
+                period,
+            });
+        }
+        self.sift_up(index);
+    }
+
+    fn remove(&mut self, index: usize) -> Slot {
+        let last = self.slots.len() - 1;
+        self.swap(index, last);
+        let slot = self.slots.pop().unwrap();
+        unsafe { *(*slot.entry).placement() = None };
+        if index < self.slots.len() {
+            self.sift_down(index);
+            self.sift_up(index);
+        }
+        slot
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.slots.swap(a, b);
+        for &index in [a, b].iter() {
+            let entry = self.slots[index].entry;
+            unsafe {
+                if let Some(placement) = (*entry).placement() {
+                    placement.index = index;
+                }
+            }
+        }
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.slots[parent].key() <= self.slots[index].key() {
+                break;
+            }
+            self.swap(parent, index);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let mut smallest = index;
+            for &child in [2 * index + 1, 2 * index + 2].iter() {
+                if child < self.slots.len() && self.slots[child].key() < self.slots[smallest].key()
+                {
+                    smallest = child;
+                }
+            }
+            if smallest == index {
+                break;
+            }
+            self.swap(index, smallest);
+            index = smallest;
+        }
+    }
+}
+
+struct Timers {
+    queues: Vec<Queue>,
+    seq: u64,
+    /// Timers in existence, every queue has room for all of them.
+    count: usize,
+}
+
+// the raw pointers in the queues are to entries owned by live `Timer`s
+unsafe impl Send for Timers {}
+
+impl Timers {
+    fn arm(&mut self, entry: &TimerEntry, deadline: Instant, period: Option<Duration>) {
+        self.disarm(entry);
+        let cpu = smp::current_id();
+        let seq = self.seq;
+        self.seq += 1;
+        let queue = &mut self.queues[cpu];
+        queue.push(
+            Slot {
+                deadline,
+                seq,
+                entry,
+            },
+            period,
+        );
+        if queue.peek().unwrap().entry == entry as *const _ {
+            program(deadline);
+        }
+    }
+
+    fn disarm(&mut self, entry: &TimerEntry) -> bool {
+        match unsafe { entry.placement() } {
+            Some(placement) => {
+                let (cpu, index) = (placement.cpu, placement.index);
+                // the queue's cpu may still get an interrupt for it, which
+                // then finds nothing due
+                self.queues[cpu].remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the next timer due at `now` off the queue of `cpu`, putting
+    /// periodic ones back for their next period.
+    fn pop_due(&mut self, cpu: usize, now: Instant) -> Option<&'static TimerEntry> {
+        let queue = &mut self.queues[cpu];
+        if queue.peek()?.deadline > now {
+            return None;
+        }
+        let period = unsafe { (*queue.peek().unwrap().entry).placement().as_ref() }
+            .and_then(|placement| placement.period);
+        let slot = queue.remove(0);
+        // the `Timer` owning it waits for `running` to clear before freeing
+        // it, so it outlives the callback
+        let entry = unsafe { &*slot.entry };
+        entry.running.store(true, Ordering::Release);
+        if let Some(period) = period {
+            // skip the periods that went by unnoticed
+            let mut deadline = slot.deadline + period;
+            if deadline <= now {
+                deadline = now + period;
+            }
+            let seq = self.seq;
+            self.seq += 1;
+            queue.push(
+                Slot {
+                    deadline,
+                    seq,
+                    entry,
+                },
+                Some(period),
+            );
+        }
+        Some(entry)
+    }
+}
+
+static TIMERS: InitCell<MutexInt<Timers>> = InitCell::new();
+
+/// Sets the local APIC timer of the running cpu to go off at `deadline`.
+/// Too far out, it goes off early and the softirq programs it again.
+fn program(deadline: Instant) {
+    let hz = LOCAL_TIMER_HZ.load(Ordering::Relaxed);
+    if hz == 0 {
+        return;
+    }
+    let nanos = deadline.duration_since(Instant::now()).as_nanos() as u64;
+    let count = (nanos as u128 * hz as u128 / 1_000_000_000) as u64;
+    irqchip::start_local_timer(count.max(1).min(u32::MAX as u64) as u32, true);
+}
+
+/// The timer softirq: runs the callbacks of the timers of this cpu that are
+/// due, then sets the local timer for the next one.
+fn run_timers() {
+    let now = Instant::now();
+    loop {
+        let entry = {
+            let mut timers = TIMERS.lock();
+            let cpu = smp::current_id();
+            match timers.pop_due(cpu, now) {
+                Some(entry) => entry,
+                None => {
+                    if let Some(next) = timers.queues[cpu].peek() {
+                        program(next.deadline);
+                    }
+                    return;
+                }
+            }
+        };
+        (entry.callback)();
+        entry.running.store(false, Ordering::Release);
+    }
+}
+
+/// Whether timers only expire on the periodic PIT tick, for lack of a
+/// one-shot timer.
+pub fn needs_tick() -> bool {
+    LOCAL_TIMER_HZ.load(Ordering::Relaxed) == 0
+}
+
+/// A one-shot or periodic timer. The callback runs in the timer softirq of
+/// the cpu that armed it, so it must not block; anything slow belongs in a
+/// `Work`.
+///
+/// Creating one allocates, so it has to happen in thread context; arming
+/// and cancelling it works from anywhere. Dropping it cancels it, and waits
+/// for the callback if it is running on another cpu; so it must not be
+/// dropped from interrupt context or from its own callback.
+pub struct Timer {
+    entry: Box<TimerEntry>,
+}
+
+impl Timer {
+    pub fn new<F>(callback: F) -> Timer
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let entry = Box::new(TimerEntry {
+            callback: Box::new(callback),
+            placement: UnsafeCell::new(None),
+            running: AtomicBool::new(false),
+        });
+        let mut timers = TIMERS.lock();
+        timers.count += 1;
+        let count = timers.count;
+        for queue in timers.queues.iter_mut() {
+            let len = queue.slots.len();
+            queue.slots.reserve(count - len);
+        }
+        Timer { entry }
+    }
+
+    /// Fires once after `delay`. Re-arms it if it was pending.
+    pub fn start(&self, delay: Duration) {
+        self.start_at(Instant::now() + delay);
+    }
+
+    /// Fires once at `deadline`, right away if that has passed.
+    pub fn start_at(&self, deadline: Instant) {
+        TIMERS.lock().arm(&self.entry, deadline, None);
+    }
+
+    /// Fires every `period`, the first time one period from now.
+    pub fn start_periodic(&self, period: Duration) {
+        let period = period.max(MIN_PERIOD);
+        TIMERS.lock().arm(&self.entry, Instant::now() + period, Some(period));
+    }
+
+    /// Stops it from firing. Returns whether it was pending; the callback
+    /// may still be running on another cpu.
+    pub fn cancel(&self) -> bool {
+        TIMERS.lock().disarm(&self.entry)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        let _timers = TIMERS.lock();
+        unsafe { self.entry.placement().is_some() }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        {
+            let mut timers = TIMERS.lock();
+            timers.disarm(&self.entry);
+            timers.count -= 1;
+        }
+        while self.entry.running.load(Ordering::Acquire) {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+/// Calls `event_handler` every `interval` for as long as the kernel runs.
+pub fn subscribe_timer<F>(interval: Duration, event_handler: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let timer = Timer::new(event_handler);
+    timer.start_periodic(interval);
+    core::mem::forget(timer);
+}
+
+/// Measures the local APIC timer against the PIT, all cpus share the rate.
+fn calibrate_local_timer() {
+    let count = x86_64::instructions::interrupts::without_interrupts(|| {
+        if !irqchip::start_local_timer(u32::MAX, false) {
+            return None;
+        }
+        pit_delay_us(CALIBRATION_US);
+        let left = irqchip::local_timer_count().unwrap();
+        irqchip::start_local_timer(0, false);
+        Some(u32::MAX - left)
+    });
+    match count {
+        Some(count) => {
+            let hz = count as u64 * 1_000_000 / CALIBRATION_US;
+            LOCAL_TIMER_HZ.store(hz, Ordering::Relaxed);
+            log::info!("timers: one-shot on the local apic timer, {} kHz", hz / 1000);
+        }
+        None => log::info!("timers: no local apic timer, expiring on the pit tick"),
+    }
+}
+
+/// Needs the heap, and the interrupt controller settled to find the local
+/// APIC timer.
+pub fn init() {
+    crate::call_stack!();
+    let queues = (0..MAX_CPUS)
+        .map(|cpu| Queue {
+            cpu,
+            slots: Vec::new(),
+        })
+        .collect();
+    TIMERS.init(MutexInt::new(
+        true,
+        Timers {
+            queues,
+            seq: 0,
+            count: 0,
+        },
+    ));
+    calibrate_local_timer();
+    open_softirq(SoftIrq::Timer, run_timers);
+}
+
+#[cfg(test)]
+use crate::kernel::{interrupt_depth, yield_now};
+
+// ids of the test timers that fired, as decimal digits in firing order
+#[cfg(test)]
+static TEST_FIRED: MutexInt<u64> = MutexInt::new(true, 0);
+
+#[cfg(test)]
+fn test_fire(id: u64) {
+    let depth = interrupt_depth();
+    assert!(depth.softirq == 1 && depth.hardirq == 0, "timer ran in {}", depth);
+    let mut fired = TEST_FIRED.lock();
+    *fired = *fired * 10 + id;
+}
+
+#[cfg(test)]
+fn test_wait(until: Instant) {
+    while Instant::now() < until {
+        yield_now();
+    }
+}
+
+#[test_case]
+fn timers_fire_in_deadline_order() {
+    *TEST_FIRED.lock() = 0;
+    let timers = [
+        Timer::new(|| test_fire(3)),
+        Timer::new(|| test_fire(1)),
+        Timer::new(|| test_fire(2)),
+    ];
+    let now = Instant::now();
+    for (timer, ms) in timers.iter().zip([3, 1, 2].iter()) {
+        timer.start_at(now + Duration::from_millis(*ms));
+    }
+    let cancelled = Timer::new(|| test_fire(4));
+    cancelled.start(Duration::from_millis(2));
+    assert!(cancelled.cancel());
+    assert!(!cancelled.cancel());
+
+    test_wait(now + Duration::from_millis(20));
+    assert_eq!(*TEST_FIRED.lock(), 123);
+    assert!(timers.iter().all(|timer| !timer.is_pending()));
+}
+
+#[test_case]
+fn periodic_timer_repeats_until_cancelled() {
+    *TEST_FIRED.lock() = 0;
+    let timer = Timer::new(|| test_fire(1));
+    timer.start_periodic(Duration::from_millis(1));
+    let start = Instant::now();
+    while *TEST_FIRED.lock() < 111 {
+        assert!(start.elapsed() < Duration::from_secs(1), "periodic timer stalled");
+        yield_now();
+    }
+    assert!(timer.is_pending());
+    assert!(timer.cancel());
+    let fired = *TEST_FIRED.lock();
+    test_wait(Instant::now() + Duration::from_millis(5));
+    assert_eq!(*TEST_FIRED.lock(), fired);
+}
+
+#[test_case]
+fn timers_fire_on_application_processors() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    if smp::cpu_count() < 2 {
+        return;
+    }
+    let fired_on = Arc::new(AtomicUsize::new(usize::MAX));
+    let handle = crate::kernel::sched::spawn("ap-timer", {
+        let fired_on = fired_on.clone();
+        move || {
+            let timer = Timer::new({
+                let fired_on = fired_on.clone();
+                move || fired_on.store(smp::current_id(), Ordering::SeqCst)
+            });
+            // arm it on whichever application processor we get to first
+            let armed_on = loop {
+                let armed = x86_64::instructions::interrupts::without_interrupts(|| {
+                    let cpu = smp::current_id();
+                    if cpu != 0 {
+                        timer.start(Duration::from_millis(2));
+                    }
+                    cpu
+                });
+                if armed != 0 {
+                    break armed;
+                }
+                yield_now();
+            };
+            let start = Instant::now();
+            while fired_on.load(Ordering::SeqCst) == usize::MAX {
+                let stalled = start.elapsed() > Duration::from_secs(1);
+                assert!(!stalled, "timer on cpu {} stalled", armed_on);
+                yield_now();
+            }
+            assert_eq!(fired_on.load(Ordering::SeqCst), armed_on);
+            0
+        }
+    });
+    assert_eq!(handle.join(), 0);
+}
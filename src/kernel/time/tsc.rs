@@ -0,0 +1,85 @@
+use super::clocksource::{register_clocksource, ClockSource};
+use super::{pit_delay_us, CALIBRATION_US};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+const CALIBRATION_ROUNDS: usize = 3;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+fn rdtsc() -> u64 {
+    unsafe {
+        let th: u32;
+        let tl: u32;
+        llvm_asm!("rdtsc" : "={eax}"(tl), "={edx}"(th) : : "edx eax" : "intel");
+        ((th as u64) << 32) | (tl as u64)
+    }
+}
+
+/// Whether the TSC ticks at a constant rate through frequency changes and
+/// sleep states, so is fit for measuring time.
+fn is_invariant() -> bool {
+    let max_extended = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0007 {
+        return false;
+    }
+    let power = unsafe { core::arch::x86_64::__cpuid(0x8000_0007) };
+    power.edx & (1 << 8) != 0
+}
+
+/// Counts TSC cycles over a known PIT delay. The shortest of a few rounds
+/// wins, longer ones were stretched by the hypervisor or the like.
+fn calibrate() -> u64 {
+    let cycles = interrupts::without_interrupts(|| {
+        (0..CALIBRATION_ROUNDS)
+            .map(|_| {
+                let start = rdtsc();
+                pit_delay_us(CALIBRATION_US);
+                rdtsc() - start
+            })
+            .min()
+            .unwrap()
+    });
+    cycles * 1_000_000 / CALIBRATION_US
+}
+
+struct Tsc;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    // the best there is, unless it changes speed with the cpu
+    fn rating(&self) -> u32 {
+        if INVARIANT.load(Ordering::Relaxed) {
+            300
+        } else {
+            100
+        }
+    }
+
+    fn frequency(&self) -> u64 {
+        FREQUENCY.load(Ordering::Relaxed)
+    }
+
+    fn read(&self) -> u64 {
+        rdtsc()
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+}
+
+static TSC: Tsc = Tsc;
+
+pub fn init() {
+    FREQUENCY.store(calibrate(), Ordering::Relaxed);
+    INVARIANT.store(is_invariant(), Ordering::Relaxed);
+    if !INVARIANT.load(Ordering::Relaxed) {
+        log::warn!("tsc: not invariant, better clock sources win over it");
+    }
+    register_clocksource(&TSC);
+}
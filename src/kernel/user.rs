@@ -0,0 +1,70 @@
+use super::gdt;
+use super::memory::user_virtual_range;
+use x86_64::VirtAddr;
+
+// IF, plus the always-one bit 1
+const USER_RFLAGS: u64 = 0x202;
+
+/// Drops the current thread to ring 3, starting at `entry` with `stack_top`
+/// as its stack pointer. The thread only comes back to the kernel through
+/// interrupts, faults and system calls.
+pub fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    assert!(user_virtual_range().contains(&entry.as_u64()));
+    assert!(user_virtual_range().contains(&(stack_top.as_u64() - 1)));
+
+    let selectors = gdt::selectors();
+    let code = selectors.user_code_selector.0 as u64;
+    let data = selectors.user_data_selector.0 as u64;
+    unsafe {
+        // nothing may interrupt between `swapgs` and `iretq`, which turns
+        // interrupts back on
+        llvm_asm!("
+            cli
+            push $0
+            push $1
+            push $2
+            push $3
+            push $4
+            swapgs
+            iretq"
+            :
+            : "r"(data), "r"(stack_top.as_u64()), "r"(USER_RFLAGS), "r"(code), "r"(entry.as_u64())
+            : "memory"
+            : "intel", "volatile");
+    }
+    unreachable!("returned from user mode")
+}
+
+/// Copies `code` into a fresh address space and runs it in ring 3 on a new
+/// thread. Returns the exit code of the thread.
+#[cfg(test)]
+pub fn run_user_code(name: &'static str, code: &[u8]) -> i32 {
+    use super::memory::{AddressSpace, Backing};
+    use alloc::sync::Arc;
+    use x86_64::structures::paging::PageTableFlags;
+
+    let space = Arc::new(AddressSpace::new());
+    let text = space.alloc_user_pages(1, PageTableFlags::empty()).unwrap();
+    let stack = space.map_anywhere(
+        1,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        Backing::Stack { limit: 16 * 4096 },
+    )
+    .unwrap();
+    let entry = text.start.start_address();
+    space.copy_to_user(entry, code).unwrap();
+    let stack_top = stack.end.start_address();
+    super::sched::spawn_in(name, space, move || enter_user_mode(entry, stack_top)).join()
+}
+
+#[test_case]
+fn user_fault_kills_thread() {
+    // mov rax, [kernel_virtual_range().start]
+    let target = super::memory::kernel_virtual_range().start;
+    let mut code = [0x48, 0xa1, 0, 0, 0, 0, 0, 0, 0, 0];
+    code[2..].copy_from_slice(&target.to_le_bytes());
+    assert_eq!(
+        run_user_code("user-fault", &code),
+        super::sched::EXIT_SEGV
+    );
+}
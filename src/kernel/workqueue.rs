@@ -0,0 +1,79 @@
+use super::sched::{self, ThreadId};
+use crate::util::init_cell::InitCell;
+use crate::util::mutex_int::MutexInt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use heapless::consts::U64;
+use heapless::Vec;
+
+/// Work to be done later by the worker thread, in thread context where it
+/// may block and take its time. Declared as a static and queued with
+/// `schedule_work`.
+pub struct Work {
+    func: fn(),
+    queued: AtomicBool,
+}
+
+impl Work {
+    pub const fn new(func: fn()) -> Work {
+        Work {
+            func,
+            queued: AtomicBool::new(false),
+        }
+    }
+}
+
+static QUEUE: InitCell<MutexInt<Vec<&'static Work, U64>>> = InitCell::new();
+static WORKER: InitCell<ThreadId> = InitCell::new();
+
+/// Queues `work` for the worker thread, from any context. Returns false if
+/// it was still queued from before, then it runs only once.
+pub fn schedule_work(work: &'static Work) -> bool {
+    if work.queued.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    QUEUE.lock().push(work).ok().expect("work queue full");
+    sched::unpark(*WORKER);
+    true
+}
+
+fn worker() -> i32 {
+    loop {
+        let works = core::mem::replace(&mut *QUEUE.lock(), Vec::new());
+        for work in works.iter() {
+            // queueing it again from here on runs it once more
+            work.queued.store(false, Ordering::Release);
+            (work.func)();
+        }
+        if works.is_empty() {
+            sched::park();
+        }
+    }
+}
+
+pub fn init() {
+    crate::call_stack!();
+    QUEUE.init(MutexInt::new(true, Vec::new()));
+    let worker = sched::spawn("kworker", worker);
+    WORKER.init(worker.id());
+}
+
+#[cfg(test)]
+static TEST_RAN_IN_THREAD: AtomicBool = AtomicBool::new(false);
+
+#[cfg(test)]
+static TEST_WORK: Work = Work::new(|| {
+    let in_thread = !super::is_interrupt_context();
+    TEST_RAN_IN_THREAD.store(in_thread, Ordering::SeqCst);
+});
+
+#[test_case]
+fn work_runs_in_thread_context() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // as if from an interrupt handler
+        let _int = super::int::InterruptContextHandle::new(super::int::ContextKind::HardIrq);
+        assert!(schedule_work(&TEST_WORK));
+    });
+    while !TEST_RAN_IN_THREAD.load(Ordering::SeqCst) {
+        sched::yield_now();
+    }
+}
@@ -5,6 +5,7 @@
 #![feature(abi_x86_interrupt)]
 #![feature(core_intrinsics)]
 #![feature(llvm_asm)]
+#![feature(global_asm)]
 #![feature(alloc_error_handler)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
@@ -60,6 +61,9 @@ pub enum QemuExitCode {
     Failed = 0x11,
 }
 
+/// Ends the run through QEMU's isa-debug-exit device. This is the test
+/// harness's backend only, the kernel itself goes down with
+/// `kernel::power::shutdown` and `kernel::power::reboot`.
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
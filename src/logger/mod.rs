@@ -1,10 +1,23 @@
-use crate::{println, serial_println};
+use crate::{kernel, println, serial_println};
+use core::fmt;
 use log::{Metadata, Record};
 
 struct SimpleLogger;
 
 static LOGGER: SimpleLogger = SimpleLogger;
 
+/// Wall clock time of a record, nothing before the RTC has been read.
+struct Stamp(Option<kernel::DateTime>);
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(time) => write!(f, "{} ", time),
+            None => Ok(()),
+        }
+    }
+}
+
 pub fn init() {
     log::set_logger(&LOGGER).expect("failed to set logger");
     log::set_max_level(log::LevelFilter::Trace);
@@ -16,8 +29,13 @@ impl log::Log for SimpleLogger {
     }
 
     fn log(&self, record: &Record) {
-        serial_println!("[{} {}:{}] {}", record.level(), record.file().unwrap(), record.line().unwrap(), record.args());
-        println!("[{} {}:{}] {}", record.level(), record.file().unwrap(), record.line().unwrap(), record.args());
+        let stamp = Stamp(kernel::wall_clock().map(kernel::DateTime::from_unix));
+        let cpu = kernel::current_cpu();
+        let depth = kernel::interrupt_depth();
+        // only tag records from interrupt context
+        let sep = if depth.is_zero() { "" } else { " " };
+        serial_println!("[{}{} cpu{}{}{} {}:{}] {}", stamp, record.level(), cpu, sep, depth, record.file().unwrap(), record.line().unwrap(), record.args());
+        println!("[{}{} cpu{}{}{} {}:{}] {}", stamp, record.level(), cpu, sep, depth, record.file().unwrap(), record.line().unwrap(), record.args());
     }
 
     fn flush(&self) {}
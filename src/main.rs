@@ -9,6 +9,9 @@ extern crate ngos;
 
 use bootloader::BootInfo;
 use core::panic::PanicInfo;
+use ngos::kernel::SchedPolicy;
+
+const SCHED_POLICY: SchedPolicy = SchedPolicy::RoundRobin;
 
 
 #[cfg(test)]
@@ -40,7 +43,8 @@ fn panic(info: &PanicInfo) -> ! {
 pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     ngos::logger::init();
     ngos::kernel::init(boot_info);
-    
+    ngos::kernel::set_sched_policy(SCHED_POLICY);
+
     ngos::vga::init_non_core();
 
     #[cfg(test)]
@@ -11,6 +11,10 @@ impl BitSet<'_> {
         BitSet { n_bits, data }
     }
 
+    pub fn len(&self) -> u64 {
+        self.n_bits
+    }
+
     pub fn set(&mut self, idx: u64, value: bool) {
         assert!(idx < self.n_bits);
         let word_mask = 1 << (idx % 8) as u8;
@@ -30,6 +34,12 @@ impl BitSet<'_> {
         assert!(range.end <= self.n_bits);
         let blk_start = num::integer::div_ceil(range.start, 8);
         let blk_end = num::integer::div_floor(range.end, 8);
+        if blk_start >= blk_end {
+            for idx in range {
+                self.set(idx, value);
+            }
+            return;
+        }
         unsafe {
             core::ptr::write_bytes(
                 self.data.as_mut_ptr().offset(blk_start as isize),
@@ -95,6 +105,12 @@ fn bitset_range() {
     for i in 123..bitset.n_bits {
         assert_eq!(bitset.get(i), false);
     }
+
+    // range within a single byte
+    bitset.set_range(3..6, false);
+    for i in 0..34 {
+        assert_eq!(bitset.get(i), !(3..6).contains(&i));
+    }
 }
 
 // use crate::util::default_in_place::DefaultInPlace;
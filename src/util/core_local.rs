@@ -1,41 +1,85 @@
-//use core::ops::Deref;
-//
-//#[macro_export]
-//macro_rules! core_local {
-//    () => {};
-//
-//    // process multiple declarations
-//    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => (
-//        $crate::__core_local_inner!($(#[$attr])* $vis $name, $t, $init);
-//        $crate::core_local!($($rest)*);
-//    );
-//
-//    // handle a single declaration
-//    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => (
-//        $crate::__core_local_inner!($(#[$attr])* $vis $name, $t, $init);
-//    );
-//}
-//
-//// this is trivially implemented
-//// only suited for single core
-//struct CoreLocal<T> {
-//    val: T
-//}
-//
-//unsafe impl<T> Sync for CoreLocal<T> {}
-//
-//impl<T> Deref for CoreLocal<T> {
-//    type Target = T;
-//
-//    fn deref(&self) -> &Self::Target {
-//        &self.val
-//    }
-//}
-//
-//#[doc(hidden)]
-//#[macro_export]
-//macro_rules! __core_local_inner {
-//    ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty, $init:expr) => {
-//        $(#[$attr])* $vis $name: $crate::CoreLocal<$t> = $crate::CoreLocal { val: $init }
-//    };
-//}
\ No newline at end of file
+use crate::kernel::MAX_CPUS;
+use x86_64::instructions::interrupts;
+
+/// Declares cpu-local statics: every cpu gets its own copy of the value,
+/// found through the per-cpu area the GS base points at in the kernel.
+/// The initializer is evaluated once per cpu and must be const.
+#[macro_export]
+macro_rules! core_local {
+    () => {};
+
+    // process multiple declarations
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => (
+        $crate::__core_local_inner!($(#[$attr])* $vis $name, $t, $init);
+        $crate::core_local!($($rest)*);
+    );
+
+    // handle a single declaration
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => (
+        $crate::__core_local_inner!($(#[$attr])* $vis $name, $t, $init);
+    );
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __core_local_inner {
+    // one initializer per cpu, `CoreLocal::new` checks there are MAX_CPUS
+    ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty, $init:expr) => {
+        $(#[$attr])* $vis static $name: $crate::util::core_local::CoreLocal<$t> =
+            $crate::util::core_local::CoreLocal::new([
+                $init, $init, $init, $init, $init, $init, $init, $init,
+                $init, $init, $init, $init, $init, $init, $init, $init,
+            ]);
+    };
+}
+
+/// A value per cpu, declared with `core_local!`.
+pub struct CoreLocal<T: 'static> {
+    values: [T; MAX_CPUS],
+}
+
+// a cpu only ever touches its own value, with interrupts off, unless `T` is
+// Sync and it goes through `on`
+unsafe impl<T: Send> Sync for CoreLocal<T> {}
+
+impl<T> CoreLocal<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> CoreLocal<T> {
+        CoreLocal { values }
+    }
+
+    /// Runs `f` on the value of the running cpu. Interrupts are off meanwhile,
+    /// so neither a handler on this cpu nor a move to another one gets in
+    /// between; `f` must not block.
+    pub fn with<R, F: FnOnce(&T) -> R>(&'static self, f: F) -> R {
+        interrupts::without_interrupts(|| f(&self.values[crate::kernel::current_cpu()]))
+    }
+
+    /// The value of cpu `id`, for values other cpus may look at.
+    pub fn on(&'static self, id: usize) -> &'static T
+    where
+        T: Sync,
+    {
+        &self.values[id]
+    }
+}
+
+#[cfg(test)]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+core_local! {
+    #[cfg(test)]
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+}
+
+#[test_case]
+fn core_local_values_are_per_cpu() {
+    interrupts::without_interrupts(|| {
+        let id = crate::kernel::current_cpu();
+        COUNTER.with(|counter| counter.fetch_add(1, Ordering::Relaxed));
+        assert_eq!(COUNTER.on(id).load(Ordering::Relaxed), 1);
+        for other in (0..MAX_CPUS).filter(|&other| other != id) {
+            assert_eq!(COUNTER.on(other).load(Ordering::Relaxed), 0);
+        }
+    });
+}
@@ -37,6 +37,17 @@ impl<T> InitCell<T> {
         inner.init = true;
     }
 
+    pub fn try_get(&self) -> Option<&T> {
+        unsafe {
+            let inner = &*self.inner.get();
+            if inner.init {
+                Some(&*inner.value.as_ptr())
+            } else {
+                None
+            }
+        }
+    }
+
     pub fn get(&self) -> &T {
         unsafe {
             let inner = &mut *self.inner.get();
@@ -2,5 +2,7 @@ pub mod init_cell;
 pub mod bit_set;
 pub mod call_stack;
 pub mod constant;
+pub mod core_local;
 pub mod mutex_int;
-pub mod default_in_place;
\ No newline at end of file
+pub mod seqlock;
+pub mod default_in_place;
@@ -1,10 +1,13 @@
+use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
 use spin::{Mutex, MutexGuard};
 
 #[derive(Debug)]
 pub struct MutexGuardInt<'a, T> {
     enable_interrupt_later: bool,
-    guard: MutexGuard<'a, T>,
+    enable_preempt_later: bool,
+    // released by hand in `drop`, before preemption and interrupts are back
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
 }
 
 pub struct MutexInt<T> {
@@ -24,11 +27,16 @@ impl<T> MutexInt<T> {
         if !self.allow_interrupt_context {
             assert!(
                 !crate::kernel::is_interrupt_context(),
-                "does not allow interrupt context"
+                "does not allow interrupt context ({})",
+                crate::kernel::interrupt_depth()
             );
+            // interrupts stay on, so keep the timer from switching threads
+            // while the lock is held
+            crate::kernel::preempt_disable();
             MutexGuardInt {
-                guard: self.inner.lock(),
+                guard: ManuallyDrop::new(self.inner.lock()),
                 enable_interrupt_later: false,
+                enable_preempt_later: true,
             }
         } else {
             let int_en = x86_64::instructions::interrupts::are_enabled();
@@ -36,8 +44,9 @@ impl<T> MutexInt<T> {
                 x86_64::instructions::interrupts::disable();
             }
             MutexGuardInt {
-                guard: self.inner.lock(),
+                guard: ManuallyDrop::new(self.inner.lock()),
                 enable_interrupt_later: int_en,
+                enable_preempt_later: false,
             }
         }
     }
@@ -45,6 +54,10 @@ impl<T> MutexInt<T> {
 
 impl<T> Drop for MutexGuardInt<'_, T> {
     fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enable_preempt_later {
+            crate::kernel::preempt_enable();
+        }
         if self.enable_interrupt_later {
             x86_64::instructions::interrupts::enable();
         }
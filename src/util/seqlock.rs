@@ -0,0 +1,57 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{fence, spin_loop_hint, AtomicU64, Ordering};
+
+/// A value readers copy out without taking a lock: they retry if a write
+/// overlapped. Writes must not race each other, callers keep a lock of their
+/// own for that; a writer interrupted by a reader on the same cpu would
+/// leave it spinning, so write with interrupts off.
+pub struct SeqLock<T: Copy> {
+    // odd while a write is in progress
+    seq: AtomicU64,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(data: T) -> SeqLock<T> {
+        SeqLock {
+            seq: AtomicU64::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before & 1 != 0 {
+                spin_loop_hint();
+                continue;
+            }
+            // may be torn, only used once the sequence shows it was not
+            let data = unsafe { core::ptr::read_volatile(self.data.get()) };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == before {
+                return data;
+            }
+        }
+    }
+
+    /// Replaces the value. Writers are serialized by the caller.
+    pub fn write(&self, data: T) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { core::ptr::write_volatile(self.data.get(), data) };
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+}
+
+#[test_case]
+fn seqlock_reads_what_was_written() {
+    let lock = SeqLock::new((1u64, 2u64));
+    assert_eq!(lock.read(), (1, 2));
+    lock.write((3, 4));
+    assert_eq!(lock.read(), (3, 4));
+    assert_eq!(lock.seq.load(Ordering::Relaxed), 2);
+}
@@ -3,10 +3,16 @@ mod text;
 
 pub use text::*;
 
+use crate::kernel::Work;
+use core::time::Duration;
+
+static REFRESH: Work = Work::new(|| TEXT_WRITER.lock().flush());
+
 pub fn init_non_core() {
     crate::call_stack!();
-    // vga refresh
-    crate::kernel::subscribe_timer(10_000_000, || {
-        TEXT_WRITER.lock().flush();
+    // vga refresh; the timer callback runs in interrupt context, so the
+    // copying is left to the worker thread
+    crate::kernel::subscribe_timer(Duration::from_millis(10), || {
+        crate::kernel::schedule_work(&REFRESH);
     });
 }
\ No newline at end of file